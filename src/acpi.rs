@@ -0,0 +1,285 @@
+// Gregory Vincent
+// just enough ACPI to find the hardware the firmware describes in its tables
+// RSDP -> RSDT/XSDT -> individual tables picked by their 4 byte signature
+use core::mem::size_of;
+use core::ptr;
+use x86_64::PhysAddr;
+use crate::memory;
+
+// every table starts with this header
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct SdtHeader{
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+// the fields of the ACPI 2.0 RSDP that we need
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct Rsdp{
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // ACPI 2.0 and up
+    length: u32,
+    xsdt_address: u64,
+}
+
+// copy a T out of physical memory - tables are packed, so never take references into them
+fn read_physical<T: Copy>(addr: PhysAddr) -> T {
+    unsafe { ptr::read_unaligned(memory::physical_to_virtual(addr).as_ptr::<T>()) }
+}
+
+// all bytes of an ACPI structure add up to 0
+fn checksum_ok(addr: PhysAddr, length: usize) -> bool {
+    let start = memory::physical_to_virtual(addr).as_ptr::<u8>();
+    let bytes = unsafe { core::slice::from_raw_parts(start, length) };
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+/**
+ * the BIOS leaves the RSDP on a 16 byte boundary, either in the first KiB of the
+ * extended BIOS data area or in the read only area between 0xE0000 and 0xFFFFF
+ */
+fn find_rsdp() -> Option<PhysAddr> {
+    // the real mode segment of the EBDA is stored at 0x40E
+    let ebda = (read_physical::<u16>(PhysAddr::new(0x40e)) as u64) << 4;
+    let areas = [(ebda, ebda + 1024), (0xe0000, 0x100000)];
+    for &(start, end) in areas.iter() {
+        if start == 0 {
+            continue;
+        }
+        for addr in (start..end).step_by(16) {
+            let addr = PhysAddr::new(addr);
+            if &read_physical::<[u8; 8]>(addr) == b"RSD PTR " && checksum_ok(addr, 20) {
+                return Some(addr);
+            }
+        }
+    }
+    None
+}
+
+/**
+ * physical address of the table with signature, ex: b"APIC" for the MADT
+ * None when there's no ACPI or the firmware didn't provide that table
+ */
+pub fn find_table(signature: &[u8; 4]) -> Option<PhysAddr> {
+    let rsdp_addr = find_rsdp()?;
+    let rsdp: Rsdp = read_physical(rsdp_addr);
+    // ACPI 2.0 has the XSDT with 64 bit pointers, 1.0 only the RSDT with 32 bit ones
+    let (root, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        (PhysAddr::new(rsdp.xsdt_address), 8)
+    } else {
+        (PhysAddr::new(rsdp.rsdt_address as u64), 4)
+    };
+    let root_header: SdtHeader = read_physical(root);
+    let entries = (root_header.length as usize).checked_sub(size_of::<SdtHeader>())? / entry_size;
+    (0..entries)
+        .map(|i| {
+            let entry = root + size_of::<SdtHeader>() + i * entry_size;
+            if entry_size == 8 {
+                PhysAddr::new(read_physical::<u64>(entry))
+            } else {
+                PhysAddr::new(read_physical::<u32>(entry) as u64)
+            }
+        })
+        .find(|&table| {
+            let header: SdtHeader = read_physical(table);
+            &header.signature == signature && checksum_ok(table, header.length as usize)
+        })
+}
+
+// fixed sizes so the MADT can be read before the heap exists
+const MAX_IO_APICS: usize = 4;
+const MAX_OVERRIDES: usize = 16;
+const MAX_LOCAL_APIC_NMIS: usize = 4;
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicInfo{
+    pub id: u8,
+    pub address: PhysAddr,
+    // first global system interrupt this I/O APIC handles
+    pub gsi_base: u32,
+}
+
+/**
+ * an ISA irq that isn't wired to the global system interrupt with the same number
+ * ex: on qemu the PIT's irq 0 arrives on GSI 2
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride{
+    pub irq: u8,
+    pub gsi: u32,
+    // MPS INTI flags - bits 0-1 polarity, bits 2-3 trigger mode
+    pub flags: u16,
+}
+
+/**
+ * a local APIC input that's wired to NMI, usually LINT1
+ * processor 0xff means every cpu
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApicNmi{
+    pub processor: u8,
+    // MPS INTI flags, like InterruptOverride
+    pub flags: u16,
+    // 0 for LINT0, 1 for LINT1
+    pub lint: u8,
+}
+
+// what the MADT says about the interrupt controllers
+#[derive(Debug, Clone, Copy)]
+pub struct Madt{
+    pub local_apic_address: PhysAddr,
+    // the machine also has 8259 PICs that have to be masked before using the APIC
+    pub has_8259: bool,
+    io_apics: [Option<IoApicInfo>; MAX_IO_APICS],
+    overrides: [Option<InterruptOverride>; MAX_OVERRIDES],
+    local_apic_nmis: [Option<LocalApicNmi>; MAX_LOCAL_APIC_NMIS],
+}
+
+impl Madt{
+    pub fn io_apics(&self) -> impl Iterator<Item = &IoApicInfo> {
+        self.io_apics.iter().flatten()
+    }
+
+    pub fn overrides(&self) -> impl Iterator<Item = &InterruptOverride> {
+        self.overrides.iter().flatten()
+    }
+
+    pub fn local_apic_nmis(&self) -> impl Iterator<Item = &LocalApicNmi> {
+        self.local_apic_nmis.iter().flatten()
+    }
+}
+
+/**
+ * the Multiple APIC Description Table, signature "APIC"
+ * header, local apic address (u32), flags (u32), then variable length entries
+ * each entry starts with its type and length
+ */
+pub fn madt() -> Option<Madt> {
+    let table = find_table(b"APIC")?;
+    let header: SdtHeader = read_physical(table);
+    let local_apic_address: u32 = read_physical(table + size_of::<SdtHeader>());
+    let flags: u32 = read_physical(table + size_of::<SdtHeader>() + 4u64);
+    let mut madt = Madt {
+        local_apic_address: PhysAddr::new(local_apic_address as u64),
+        has_8259: flags & 1 != 0,
+        io_apics: [None; MAX_IO_APICS],
+        overrides: [None; MAX_OVERRIDES],
+        local_apic_nmis: [None; MAX_LOCAL_APIC_NMIS],
+    };
+
+    let end = table + header.length as u64;
+    let mut entry = table + size_of::<SdtHeader>() + 8u64;
+    while entry + 2u64 <= end {
+        let entry_type: u8 = read_physical(entry);
+        let length: u8 = read_physical(entry + 1u64);
+        if length < 2 {
+            break;
+        }
+        match entry_type {
+            // I/O APIC: id, reserved, address (u32), gsi base (u32)
+            1 => {
+                let info = IoApicInfo {
+                    id: read_physical(entry + 2u64),
+                    address: PhysAddr::new(read_physical::<u32>(entry + 4u64) as u64),
+                    gsi_base: read_physical(entry + 8u64),
+                };
+                if let Some(slot) = madt.io_apics.iter_mut().find(|slot| slot.is_none()) {
+                    *slot = Some(info);
+                }
+            }
+            // interrupt source override: bus, irq, gsi (u32), flags (u16)
+            2 => {
+                let info = InterruptOverride {
+                    irq: read_physical(entry + 3u64),
+                    gsi: read_physical(entry + 4u64),
+                    flags: read_physical(entry + 8u64),
+                };
+                if let Some(slot) = madt.overrides.iter_mut().find(|slot| slot.is_none()) {
+                    *slot = Some(info);
+                }
+            }
+            // local apic NMI: processor id, flags (u16), lint
+            4 => {
+                let info = LocalApicNmi {
+                    processor: read_physical(entry + 2u64),
+                    flags: read_physical(entry + 3u64),
+                    lint: read_physical(entry + 5u64),
+                };
+                if let Some(slot) = madt.local_apic_nmis.iter_mut().find(|slot| slot.is_none()) {
+                    *slot = Some(info);
+                }
+            }
+            // local apic address override: reserved (u16), address (u64)
+            5 => {
+                madt.local_apic_address = PhysAddr::new(read_physical(entry + 4u64));
+            }
+            _ => {}
+        }
+        entry += length as u64;
+    }
+    Some(madt)
+}
+
+// what the HPET table says about the high precision event timer
+#[derive(Debug, Clone, Copy)]
+pub struct HpetInfo{
+    pub address: PhysAddr,
+    // which HPET this is, when there's more than one
+    pub number: u8,
+    // smallest period in counter ticks the HPET can do without losing interrupts
+    pub min_tick: u16,
+}
+
+/**
+ * the HPET Description Table, signature "HPET"
+ * header, event timer block id (u32), base address as a generic address structure
+ * (address space u8, bit width u8, bit offset u8, access size u8, address u64),
+ * hpet number (u8), minimum tick (u16), page protection (u8)
+ */
+pub fn hpet() -> Option<HpetInfo> {
+    let table = find_table(b"HPET")?;
+    let gas = table + size_of::<SdtHeader>() + 4u64;
+    // 0 is system memory, the other address spaces don't make sense for an HPET
+    if read_physical::<u8>(gas) != 0 {
+        return None;
+    }
+    Some(HpetInfo {
+        address: PhysAddr::new(read_physical(gas + 4u64)),
+        number: read_physical(gas + 12u64),
+        min_tick: read_physical(gas + 13u64),
+    })
+}
+
+#[test_case]
+fn test_madt_is_found(){
+    // qemu always provides ACPI tables with an I/O APIC in them
+    let madt = madt().expect("no MADT");
+    assert!(madt.io_apics().count() >= 1);
+}
+
+#[test_case]
+fn test_madt_has_local_apic_nmi(){
+    // qemu wires LINT1 of every cpu to NMI
+    let madt = madt().expect("no MADT");
+    assert!(madt.local_apic_nmis().any(|nmi| nmi.lint == 1));
+}
+
+#[test_case]
+fn test_hpet_is_found(){
+    // qemu's pc machine has an HPET unless it's turned off
+    let hpet = hpet().expect("no HPET table");
+    assert_eq!(hpet.address.as_u64(), 0xfed0_0000);
+}
@@ -0,0 +1,193 @@
+// Gregory Vincent
+// kernel heap - lets the kernel use Box, Vec, String, etc from the alloc crate
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::ptr;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::{mapper::MapToError, Page, PageSize, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+use crate::memory;
+
+/**
+ * three allocator designs to compare, picked with a cargo feature
+ * bump - fastest, but memory only comes back once everything is freed
+ * linked_list - reuses any freed region, merging neighbours back together
+ * fixed_size_block - lists of common block sizes, falls back to linked_list for big ones
+ */
+pub mod bump;
+pub mod linked_list;
+pub mod fixed_size_block;
+
+/**
+ * heap lives at a fixed virtual range that nothing else maps
+ * easy to recognize a heap pointer when debugging
+ */
+pub const HEAP_START: usize = 0x_4444_4444_0000;
+// size mapped up front by init_heap
+pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+// the heap grows on demand, but never past HEAP_START + HEAP_MAX_SIZE
+pub const HEAP_MAX_SIZE: usize = 16 * 1024 * 1024; // 16 MiB
+// grow at least this much at once so a burst of allocations doesn't map one page at a time
+const HEAP_GROWTH_STEP: usize = 64 * 1024; // 64 KiB
+
+/**
+ * every Box::new, Vec::push, etc goes through here
+ * features are checked in order - bump, then linked list, otherwise fixed size block
+ * ex: cargo test --features bump-allocator
+ */
+#[cfg(feature = "bump-allocator")]
+#[global_allocator]
+static ALLOCATOR: Locked<bump::BumpAllocator> = Locked::new(bump::BumpAllocator::new());
+
+#[cfg(all(feature = "linked-list-allocator", not(feature = "bump-allocator")))]
+#[global_allocator]
+static ALLOCATOR: Locked<linked_list::LinkedListAllocator> =
+    Locked::new(linked_list::LinkedListAllocator::new());
+
+#[cfg(not(any(feature = "bump-allocator", feature = "linked-list-allocator")))]
+#[global_allocator]
+static ALLOCATOR: Locked<fixed_size_block::FixedSizeBlockAllocator> =
+    Locked::new(fixed_size_block::FixedSizeBlockAllocator::new());
+
+/**
+ * back the heap range with physical frames, then hand it to the allocator
+ * needs memory::init and memory::init_frame_allocator to have been called first
+ */
+pub fn init_heap() -> Result<(), MapToError<Size4KiB>> {
+    let page_range = {
+        let heap_start = VirtAddr::new(HEAP_START as u64);
+        let heap_end = heap_start + HEAP_SIZE - 1u64;
+        let heap_start_page = Page::containing_address(heap_start);
+        let heap_end_page = Page::containing_address(heap_end);
+        Page::range_inclusive(heap_start_page, heap_end_page)
+    };
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    for page in page_range {
+        memory::map_page(page, flags)?;
+    }
+    // unsafe - the range is mapped and unused, and this only runs once
+    unsafe {
+        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+    }
+    Ok(())
+}
+
+// first address past the mapped heap, HEAP_START + HEAP_SIZE until it has grown
+pub fn heap_end() -> usize {
+    ALLOCATOR.lock().heap_end()
+}
+
+/**
+ * what every allocator design provides, so the heap can grow the same way for all of them
+ * allocate returns null when the allocator is out of memory
+ */
+pub trait HeapAllocator {
+    fn allocate(&mut self, layout: Layout) -> *mut u8;
+    /**
+     * # Safety
+     * ptr has to have come from allocate with the same layout
+     */
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout);
+    // first address past the memory the allocator manages
+    fn heap_end(&self) -> usize;
+    /**
+     * # Safety
+     * the size bytes starting at heap_end have to be mapped and unused
+     */
+    unsafe fn extend(&mut self, size: usize);
+}
+
+/**
+ * interrupts are off while the allocator is locked, an interrupt handler that frees
+ * a Box would otherwise spin forever on the lock the code it interrupted holds
+ * Lock order: ALLOCATOR, then memory::MAPPER, then memory::FRAME_ALLOCATOR
+ * so nothing may allocate while holding MAPPER or FRAME_ALLOCATOR
+ */
+unsafe impl<A: HeapAllocator> GlobalAlloc for Locked<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        interrupts::without_interrupts(|| {
+            let mut allocator = self.lock();
+            let ptr = allocator.allocate(layout);
+            if !ptr.is_null() {
+                return ptr;
+            }
+            // out of memory - map more pages onto the end of the heap and try once more
+            if grow_heap(&mut *allocator, layout) {
+                allocator.allocate(layout)
+            } else {
+                ptr::null_mut()
+            }
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        interrupts::without_interrupts(|| self.lock().deallocate(ptr, layout))
+    }
+}
+
+/**
+ * map enough new pages after heap_end to fit layout and give them to the allocator
+ * false if the heap is at its ceiling or there are no physical frames left
+ * runs with the allocator locked, so the page table code must never allocate on the heap
+ */
+fn grow_heap<A: HeapAllocator>(allocator: &mut A, layout: Layout) -> bool {
+    let heap_end = allocator.heap_end();
+    let heap_limit = HEAP_START + HEAP_MAX_SIZE;
+    // only the kernel heap grows, not an allocator set up over some other memory
+    if heap_end < HEAP_START || heap_end >= heap_limit {
+        return false;
+    }
+    // worst case the allocation needs a whole alignment's worth of padding in front of it
+    // plus room for a free list node after it, a smaller leftover can't be split off
+    let needed = layout.size()
+        .saturating_add(layout.align())
+        .saturating_add(linked_list::NODE_SIZE);
+    // never fits under the ceiling, don't map pages that would only sit there unused
+    if needed > heap_limit - heap_end {
+        return false;
+    }
+    let growth = align_up(needed.max(HEAP_GROWTH_STEP), Size4KiB::SIZE as usize)
+        .min(heap_limit - heap_end);
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let start_page = Page::<Size4KiB>::containing_address(VirtAddr::new(heap_end as u64));
+    let end_page = Page::containing_address(VirtAddr::new((heap_end + growth - 1) as u64));
+    let mut mapped = 0;
+    for page in Page::range_inclusive(start_page, end_page) {
+        if memory::map_page(page, flags).is_err() {
+            break;
+        }
+        mapped += Size4KiB::SIZE as usize;
+    }
+    if mapped == 0 {
+        return false;
+    }
+    // hand over whatever got mapped, even if we ran out of frames part way
+    unsafe { allocator.extend(mapped) };
+    true
+}
+
+/**
+ * GlobalAlloc only hands out &self, but allocators need to change their state
+ * wrapping them in a spinlock gives us the interior mutability
+ * a local wrapper type is needed since we can't implement GlobalAlloc on spin::Mutex directly
+ */
+pub struct Locked<A> {
+    inner: spin::Mutex<A>,
+}
+
+impl<A> Locked<A> {
+    pub const fn new(inner: A) -> Self {
+        Locked {
+            inner: spin::Mutex::new(inner),
+        }
+    }
+
+    pub fn lock(&self) -> spin::MutexGuard<'_, A> {
+        self.inner.lock()
+    }
+}
+
+// round addr up to the next multiple of align - align has to be a power of 2
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
@@ -0,0 +1,97 @@
+// bump allocator - hands out memory by moving a pointer forward
+// can only reuse memory once every allocation has been freed
+use super::{align_up, HeapAllocator};
+use alloc::alloc::Layout;
+use core::ptr;
+
+pub struct BumpAllocator {
+    heap_start: usize,
+    heap_end: usize,
+    // start of the unused memory - everything below it has been handed out
+    next: usize,
+    // number of live allocations, when it hits 0 the whole heap is free again
+    allocations: usize,
+}
+
+impl BumpAllocator {
+    // empty allocator - can't hand anything out until init is called
+    pub const fn new() -> Self {
+        BumpAllocator {
+            heap_start: 0,
+            heap_end: 0,
+            next: 0,
+            allocations: 0,
+        }
+    }
+
+    /**
+     * # Safety
+     * the caller has to guarantee the range is mapped and unused
+     * and this should only be called once
+     */
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.heap_start = heap_start;
+        self.heap_end = heap_start + heap_size;
+        self.next = heap_start;
+    }
+}
+
+impl Default for BumpAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HeapAllocator for BumpAllocator {
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let alloc_start = align_up(self.next, layout.align());
+        let alloc_end = match alloc_start.checked_add(layout.size()) {
+            Some(end) => end,
+            None => return ptr::null_mut(),
+        };
+        if alloc_end > self.heap_end {
+            // out of memory
+            ptr::null_mut()
+        } else {
+            self.next = alloc_end;
+            self.allocations += 1;
+            alloc_start as *mut u8
+        }
+    }
+
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        self.allocations -= 1;
+        if self.allocations == 0 {
+            self.next = self.heap_start;
+        } else if ptr as usize + layout.size() == self.next {
+            // the most recent allocation can be rolled back on its own
+            self.next = ptr as usize;
+        }
+    }
+
+    fn heap_end(&self) -> usize {
+        self.heap_end
+    }
+
+    unsafe fn extend(&mut self, size: usize) {
+        self.heap_end += size;
+    }
+}
+
+#[test_case]
+fn test_bump_resets_when_everything_is_freed(){
+    let mut buffer = [0u64; 16];
+    let mut allocator = BumpAllocator::new();
+    unsafe { allocator.init(buffer.as_mut_ptr() as usize, 128) };
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let first = allocator.allocate(layout);
+    let second = allocator.allocate(layout);
+    assert!(!first.is_null() && !second.is_null());
+    // the heap is full
+    assert!(allocator.allocate(layout).is_null());
+    unsafe {
+        allocator.deallocate(first, layout);
+        allocator.deallocate(second, layout);
+    }
+    assert_eq!(allocator.allocate(layout), first);
+}
@@ -0,0 +1,123 @@
+// fixed size block allocator - rounds allocations up to one of a few block sizes
+// and keeps a list of freed blocks for each size, so alloc and dealloc are just a list push/pop
+use super::linked_list::LinkedListAllocator;
+use super::HeapAllocator;
+use alloc::alloc::Layout;
+use core::mem;
+
+/**
+ * block sizes are powers of 2 so each one is also a valid alignment
+ * no 4 byte blocks - every block has to be able to hold a ListNode once freed
+ * anything bigger than the last size goes to the fallback allocator
+ */
+const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+// written into a freed block, blocks of one size don't need a size field
+struct ListNode {
+    next: Option<&'static mut ListNode>,
+}
+
+pub struct FixedSizeBlockAllocator {
+    // list_heads[i] is the list of free blocks of size BLOCK_SIZES[i]
+    list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
+    // new blocks and large allocations come from here
+    fallback_allocator: LinkedListAllocator,
+}
+
+impl FixedSizeBlockAllocator {
+    // empty allocator - can't hand anything out until init is called
+    pub const fn new() -> Self {
+        // Option<&mut> isn't Copy, so the array needs a const to repeat
+        const EMPTY: Option<&'static mut ListNode> = None;
+        FixedSizeBlockAllocator {
+            list_heads: [EMPTY; BLOCK_SIZES.len()],
+            fallback_allocator: LinkedListAllocator::new(),
+        }
+    }
+
+    /**
+     * # Safety
+     * the caller has to guarantee the range is mapped and unused
+     * and this should only be called once
+     */
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.fallback_allocator.init(heap_start, heap_size);
+    }
+
+    fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
+        self.fallback_allocator.allocate(layout)
+    }
+}
+
+// index of the smallest block size that fits the layout, None if it's too big for any
+fn list_index(layout: &Layout) -> Option<usize> {
+    let required_block_size = layout.size().max(layout.align());
+    BLOCK_SIZES.iter().position(|&size| size >= required_block_size)
+}
+
+impl Default for FixedSizeBlockAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HeapAllocator for FixedSizeBlockAllocator {
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        match list_index(&layout) {
+            Some(index) => match self.list_heads[index].take() {
+                // reuse a freed block
+                Some(node) => {
+                    self.list_heads[index] = node.next.take();
+                    node as *mut ListNode as *mut u8
+                }
+                // no free block of that size yet, carve a new one out of the fallback
+                None => {
+                    let block_size = BLOCK_SIZES[index];
+                    let block_align = block_size;
+                    let layout = Layout::from_size_align(block_size, block_align).unwrap();
+                    self.fallback_alloc(layout)
+                }
+            },
+            None => self.fallback_alloc(layout),
+        }
+    }
+
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        match list_index(&layout) {
+            Some(index) => {
+                // the block goes onto its size's list, not back to the fallback
+                let new_node = ListNode {
+                    next: self.list_heads[index].take(),
+                };
+                assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
+                assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
+                let new_node_ptr = ptr as *mut ListNode;
+                new_node_ptr.write(new_node);
+                self.list_heads[index] = Some(&mut *new_node_ptr);
+            }
+            None => self.fallback_allocator.deallocate(ptr, layout),
+        }
+    }
+
+    fn heap_end(&self) -> usize {
+        self.fallback_allocator.heap_end()
+    }
+
+    // new blocks are carved out of the fallback, so that's where new memory goes
+    unsafe fn extend(&mut self, size: usize) {
+        self.fallback_allocator.extend(size);
+    }
+}
+
+#[test_case]
+fn test_freed_block_is_reused(){
+    let mut buffer = [0u64; 64];
+    let mut allocator = FixedSizeBlockAllocator::new();
+    unsafe { allocator.init(buffer.as_mut_ptr() as usize, 512) };
+    let layout = Layout::from_size_align(24, 8).unwrap();
+    let block = allocator.allocate(layout);
+    assert!(!block.is_null());
+    unsafe { allocator.deallocate(block, layout) };
+    // same size class, so the freed block comes straight back
+    assert_eq!(allocator.allocate(Layout::from_size_align(32, 8).unwrap()), block);
+}
@@ -0,0 +1,211 @@
+// linked list allocator - keeps the freed regions of the heap in a list
+// the list lives inside the free memory itself, so it costs no extra space
+use super::{align_up, HeapAllocator};
+use alloc::alloc::Layout;
+use core::{mem, ptr};
+
+/**
+ * header written at the start of every free region
+ * the list is sorted by address so neighbouring regions can be merged
+ */
+struct ListNode {
+    size: usize,
+    next: Option<&'static mut ListNode>,
+}
+
+impl ListNode {
+    const fn new(size: usize) -> Self {
+        ListNode { size, next: None }
+    }
+
+    fn start_addr(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    fn end_addr(&self) -> usize {
+        self.start_addr() + self.size
+    }
+}
+
+// the smallest region the list can hold, so the least a region can be split by
+pub(super) const NODE_SIZE: usize = mem::size_of::<ListNode>();
+
+pub struct LinkedListAllocator {
+    // dummy node with size 0 - its next is the first real free region
+    head: ListNode,
+    heap_end: usize,
+}
+
+impl LinkedListAllocator {
+    // empty allocator - can't hand anything out until init is called
+    pub const fn new() -> Self {
+        LinkedListAllocator {
+            head: ListNode::new(0),
+            heap_end: 0,
+        }
+    }
+
+    /**
+     * # Safety
+     * the caller has to guarantee the range is mapped and unused
+     * and this should only be called once
+     */
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.add_free_region(heap_start, heap_size);
+        self.heap_end = heap_start + heap_size;
+    }
+
+    /**
+     * put a region back into the list at its sorted position
+     * then merge it with the regions right before and after it if they touch
+     * # Safety
+     * the region has to be unused
+     */
+    unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
+        // the region has to be able to hold a ListNode
+        assert_eq!(align_up(addr, mem::align_of::<ListNode>()), addr);
+        assert!(size >= mem::size_of::<ListNode>());
+
+        // find the last region that starts before this one
+        let mut previous = &mut self.head;
+        while previous.next.as_ref().is_some_and(|next| next.start_addr() < addr) {
+            previous = previous.next.as_mut().unwrap();
+        }
+
+        let mut node = ListNode::new(size);
+        node.next = previous.next.take();
+        let node_ptr = addr as *mut ListNode;
+        node_ptr.write(node);
+        let node = &mut *node_ptr;
+
+        // merge with the region after
+        if node.next.as_ref().is_some_and(|next| next.start_addr() == node.end_addr()) {
+            let next = node.next.take().unwrap();
+            node.size += next.size;
+            node.next = next.next.take();
+        }
+        // merge with the region before - the dummy head is never a real region
+        if previous.size != 0 && previous.end_addr() == node.start_addr() {
+            previous.size += node.size;
+            previous.next = node.next.take();
+        } else {
+            previous.next = Some(node);
+        }
+    }
+
+    /**
+     * unlink the first region big enough for the allocation
+     * returns the region and where in it the allocation starts
+     */
+    fn find_region(&mut self, size: usize, align: usize) -> Option<(&'static mut ListNode, usize)> {
+        let mut current = &mut self.head;
+        while let Some(ref mut region) = current.next {
+            if let Ok(alloc_start) = Self::alloc_from_region(region, size, align) {
+                let next = region.next.take();
+                let found = current.next.take().unwrap();
+                current.next = next;
+                return Some((found, alloc_start));
+            } else {
+                current = current.next.as_mut().unwrap();
+            }
+        }
+        None
+    }
+
+    /**
+     * where the allocation would start inside region, or Err if it doesn't fit
+     * the leftover memory before and after it has to be able to hold a ListNode,
+     * otherwise it could never be put back into the list
+     */
+    fn alloc_from_region(region: &ListNode, size: usize, align: usize) -> Result<usize, ()> {
+        let mut alloc_start = align_up(region.start_addr(), align);
+        let front_padding = alloc_start - region.start_addr();
+        if front_padding > 0 && front_padding < mem::size_of::<ListNode>() {
+            // skip ahead far enough to leave room for a ListNode in front
+            alloc_start = align_up(region.start_addr() + mem::size_of::<ListNode>(), align);
+        }
+        let alloc_end = alloc_start.checked_add(size).ok_or(())?;
+        if alloc_end > region.end_addr() {
+            return Err(());
+        }
+        let excess_size = region.end_addr() - alloc_end;
+        if excess_size > 0 && excess_size < mem::size_of::<ListNode>() {
+            return Err(());
+        }
+        Ok(alloc_start)
+    }
+
+    // every allocation has to be big and aligned enough to become a ListNode once freed
+    fn size_align(layout: Layout) -> (usize, usize) {
+        let layout = layout
+            .align_to(mem::align_of::<ListNode>())
+            .expect("adjusting alignment failed")
+            .pad_to_align();
+        let size = layout.size().max(mem::size_of::<ListNode>());
+        (size, layout.align())
+    }
+}
+
+impl Default for LinkedListAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HeapAllocator for LinkedListAllocator {
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let (size, align) = Self::size_align(layout);
+        if let Some((region, alloc_start)) = self.find_region(size, align) {
+            // region gets overwritten once its leftovers go back into the list
+            let region_start = region.start_addr();
+            let region_end = region.end_addr();
+            let alloc_end = alloc_start + size;
+            unsafe {
+                if alloc_start > region_start {
+                    self.add_free_region(region_start, alloc_start - region_start);
+                }
+                if region_end > alloc_end {
+                    self.add_free_region(alloc_end, region_end - alloc_end);
+                }
+            }
+            alloc_start as *mut u8
+        } else {
+            ptr::null_mut()
+        }
+    }
+
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = Self::size_align(layout);
+        self.add_free_region(ptr as usize, size);
+    }
+
+    fn heap_end(&self) -> usize {
+        self.heap_end
+    }
+
+    // the new memory merges into the last free region if that one reaches the old end
+    unsafe fn extend(&mut self, size: usize) {
+        self.add_free_region(self.heap_end, size);
+        self.heap_end += size;
+    }
+}
+
+#[test_case]
+fn test_freed_neighbours_coalesce(){
+    let mut buffer = [0u64; 32];
+    let mut allocator = LinkedListAllocator::new();
+    unsafe { allocator.init(buffer.as_mut_ptr() as usize, 256) };
+    let half = Layout::from_size_align(128, 8).unwrap();
+    let first = allocator.allocate(half);
+    let second = allocator.allocate(half);
+    assert!(!first.is_null() && !second.is_null());
+    // the heap is full
+    assert!(allocator.allocate(half).is_null());
+    unsafe {
+        // freeing in either order should leave one region covering the whole heap
+        allocator.deallocate(second, half);
+        allocator.deallocate(first, half);
+    }
+    let whole = Layout::from_size_align(256, 8).unwrap();
+    assert_eq!(allocator.allocate(whole), buffer.as_mut_ptr() as *mut u8);
+}
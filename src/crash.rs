@@ -0,0 +1,243 @@
+// Gregory Vincent
+// crash reports - everything we know about the cpu when an exception or panic takes the kernel down
+// written to the screen and to serial, so the logs from cargo test have the whole picture
+use core::arch::asm;
+use core::fmt;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::registers::rflags::{self, RFlags};
+use x86_64::structures::idt::{ExceptionVector, InterruptStackFrameValue};
+use x86_64::VirtAddr;
+use crate::interrupts::exceptions::{ExceptionContext, Registers};
+use crate::{memory, symbols};
+
+// frames printed before giving up - a corrupted stack could otherwise loop forever
+const MAX_BACKTRACE_DEPTH: usize = 32;
+
+/**
+ * only the first report gets printed
+ * a fatal exception reports and then panics, and a report that faults or panics itself
+ * would otherwise start another one
+ */
+static REPORTED: AtomicBool = AtomicBool::new(false);
+
+// same line to the vga buffer and to serial
+macro_rules! report {
+    ($($arg:tt)*) => {{
+        $crate::println!($($arg)*);
+        $crate::serial_println!($($arg)*);
+    }};
+}
+
+/**
+ * crash report for an exception the kernel can't recover from
+ * registers are the ones the exception stub saved, so they're what the faulting code saw
+ */
+pub fn report_exception(vector: ExceptionVector, context: &ExceptionContext, error_code: Option<&dyn fmt::Debug>){
+    if REPORTED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    report!("==================== CRASH REPORT ====================");
+    report!("EXCEPTION: {:?} (vector {})", vector, vector as u8);
+    // the raw value only means something for exceptions the cpu pushes an error code for
+    match error_code {
+        Some(error_code) if pushes_error_code(vector) => report!("error code: {:?} ({:#x})", error_code, context.error_code),
+        Some(cause) => report!("cause: {:?}", cause),
+        None => {}
+    }
+    report_cpu_state(&context.registers, &context.stack_frame);
+    // the faulting instruction first, then whoever called the function it was in
+    report_backtrace(Some(context.stack_frame.instruction_pointer), context.registers.rbp);
+    report!("======================================================");
+}
+
+fn pushes_error_code(vector: ExceptionVector) -> bool {
+    use ExceptionVector::*;
+    matches!(vector, Double | InvalidTss | SegmentNotPresent | Stack | GeneralProtection
+        | Page | AlignmentCheck | ControlProtection | VmmCommunication | Security)
+}
+
+/**
+ * crash report for a panic - registers are captured inside this function,
+ * so the backtrace is what actually says where the panic came from
+ * does nothing if an exception already reported the crash
+ */
+pub fn report_panic(info: &PanicInfo){
+    let registers = capture_registers();
+    if REPORTED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    report!("==================== CRASH REPORT ====================");
+    report!("PANIC: {}", info);
+    report_cpu_state(&registers, &current_stack_frame());
+    report_backtrace(None, registers.rbp);
+    report!("======================================================");
+}
+
+fn report_cpu_state(registers: &Registers, frame: &InterruptStackFrameValue){
+    let r = registers;
+    report!("RIP {:#06x}:{:#018x}  RSP {:#06x}:{:#018x}",
+        frame.code_segment, frame.instruction_pointer.as_u64(),
+        frame.stack_segment, frame.stack_pointer.as_u64());
+    report!("RAX {:#018x}  RBX {:#018x}  RCX {:#018x}", r.rax, r.rbx, r.rcx);
+    report!("RDX {:#018x}  RSI {:#018x}  RDI {:#018x}", r.rdx, r.rsi, r.rdi);
+    report!("RBP {:#018x}  R8  {:#018x}  R9  {:#018x}", r.rbp, r.r8, r.r9);
+    report!("R10 {:#018x}  R11 {:#018x}  R12 {:#018x}", r.r10, r.r11, r.r12);
+    report!("R13 {:#018x}  R14 {:#018x}  R15 {:#018x}", r.r13, r.r14, r.r15);
+    report!("RFLAGS {:#018x} {:?}", frame.cpu_flags, RFlags::from_bits_truncate(frame.cpu_flags));
+    report!("CR0 {:#018x} {:?}", Cr0::read_raw(), Cr0::read());
+    // only meaningful after a page fault - the address that was accessed
+    report!("CR2 {:#018x}", Cr2::read().as_u64());
+    let (level_4_table, cr3_flags) = Cr3::read();
+    report!("CR3 {:#018x} {:?}", level_4_table.start_address().as_u64(), cr3_flags);
+    report!("CR4 {:#018x} {:?}", Cr4::read_raw(), Cr4::read());
+}
+
+fn report_backtrace(instruction_pointer: Option<VirtAddr>, rbp: u64){
+    report!("backtrace:");
+    let mut depth = 0;
+    if let Some(address) = instruction_pointer {
+        report_frame(depth, address.as_u64(), symbols::lookup(address.as_u64()));
+        depth += 1;
+    }
+    for address in Backtrace::new(rbp) {
+        report_frame(depth, address.as_u64(), return_address_symbol(address.as_u64()));
+        depth += 1;
+    }
+    if !symbols::is_loaded() {
+        report!("  (no symbol table - run through tools/runner.sh to get function names)");
+    }
+}
+
+// the call is the instruction before the return address, and it might be the last one in its function
+fn return_address_symbol(address: u64) -> Option<symbols::Symbol> {
+    let mut symbol = symbols::lookup(address.checked_sub(1)?)?;
+    symbol.offset += 1;
+    Some(symbol)
+}
+
+fn report_frame(depth: usize, address: u64, symbol: Option<symbols::Symbol>){
+    match symbol {
+        Some(symbol) => report!("  #{:<2} {:#018x} {}", depth, address, symbol),
+        None => report!("  #{:<2} {:#018x} ???", depth, address),
+    }
+}
+
+/**
+ * walks the chain of saved frame pointers, yielding the return address of each frame
+ * every function starts with push rbp; mov rbp, rsp - so rbp points at the caller's rbp
+ * with the return address right above it
+ * only works because the target json forces frame pointers on
+ */
+pub struct Backtrace{
+    rbp: u64,
+    depth: usize,
+}
+
+impl Backtrace{
+    pub fn new(rbp: u64) -> Self {
+        Backtrace { rbp, depth: 0 }
+    }
+}
+
+impl Iterator for Backtrace{
+    type Item = VirtAddr;
+
+    fn next(&mut self) -> Option<VirtAddr> {
+        // 0 marks the end - switch_to clears rbp before the first function on a new stack
+        if self.rbp == 0 || self.depth >= MAX_BACKTRACE_DEPTH {
+            return None;
+        }
+        // a crash can leave anything in rbp, never read through a pointer we can't trust
+        if !self.rbp.is_multiple_of(8) || !is_mapped(self.rbp) || !is_mapped(self.rbp + 8) {
+            return None;
+        }
+        let saved_rbp = unsafe { *(self.rbp as *const u64) };
+        let return_address = unsafe { *((self.rbp + 8) as *const u64) };
+        if return_address == 0 {
+            return None;
+        }
+        // stacks grow down, so the caller's frame is always at a higher address
+        self.rbp = if saved_rbp > self.rbp { saved_rbp } else { 0 };
+        self.depth += 1;
+        Some(VirtAddr::new_truncate(return_address))
+    }
+}
+
+// try_translate_addr won't spin if the crash happened with the page tables locked,
+// the backtrace just stops there
+fn is_mapped(addr: u64) -> bool {
+    match VirtAddr::try_new(addr) {
+        Ok(addr) => memory::try_translate_addr(addr).is_some(),
+        Err(_) => false,
+    }
+}
+
+/**
+ * snapshot of the general purpose registers as they are right now
+ * inline so rbp is the frame of the function asking, not of this one
+ */
+#[inline(always)]
+fn capture_registers() -> Registers {
+    let mut registers = Registers::default();
+    let registers_ptr: *mut Registers = &mut registers;
+    // offsets follow the field order of Registers
+    unsafe {
+        asm!(
+            "mov [{0} + 0x00], r15",
+            "mov [{0} + 0x08], r14",
+            "mov [{0} + 0x10], r13",
+            "mov [{0} + 0x18], r12",
+            "mov [{0} + 0x20], r11",
+            "mov [{0} + 0x28], r10",
+            "mov [{0} + 0x30], r9",
+            "mov [{0} + 0x38], r8",
+            "mov [{0} + 0x40], rbp",
+            "mov [{0} + 0x48], rdi",
+            "mov [{0} + 0x50], rsi",
+            "mov [{0} + 0x58], rdx",
+            "mov [{0} + 0x60], rcx",
+            "mov [{0} + 0x68], rbx",
+            "mov [{0} + 0x70], rax",
+            in(reg) registers_ptr,
+            options(nostack, preserves_flags),
+        );
+    }
+    registers
+}
+
+// what the cpu would push on an interrupt, but for the code that's running now
+#[inline(always)]
+fn current_stack_frame() -> InterruptStackFrameValue {
+    use x86_64::instructions::segmentation::{Segment, CS, SS};
+    let instruction_pointer: u64;
+    let stack_pointer: u64;
+    unsafe {
+        asm!("lea {}, [rip]", out(reg) instruction_pointer, options(nomem, nostack, preserves_flags));
+        asm!("mov {}, rsp", out(reg) stack_pointer, options(nomem, nostack, preserves_flags));
+    }
+    InterruptStackFrameValue {
+        instruction_pointer: VirtAddr::new_truncate(instruction_pointer),
+        code_segment: CS::get_reg().0 as u64,
+        cpu_flags: rflags::read_raw(),
+        stack_pointer: VirtAddr::new_truncate(stack_pointer),
+        stack_segment: SS::get_reg().0 as u64,
+    }
+}
+
+#[test_case]
+fn test_backtrace_finds_callers(){
+    // at least the test runner called us
+    let registers = capture_registers();
+    assert!(Backtrace::new(registers.rbp).count() >= 1);
+}
+
+#[test_case]
+fn test_backtrace_rejects_bad_frame_pointers(){
+    assert_eq!(Backtrace::new(0).count(), 0);
+    // not 8 byte aligned
+    assert_eq!(Backtrace::new(0x1003).count(), 0);
+    // not mapped
+    assert_eq!(Backtrace::new(0x_7777_7777_0000).count(), 0);
+}
@@ -0,0 +1,91 @@
+// the shared half of tests/exception_*.rs
+// a fatal exception can't be recovered from, so every exception gets a test kernel of its own
+// the test triggers it, and the panic that follows the crash report checks what the handler
+// wrote over serial - the vector and the decoded error code
+use bootloader::BootInfo;
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use spin::Mutex;
+use x86_64::structures::idt::ExceptionVector;
+use crate::{exit_qemu, memory, serial, serial_print, serial_println, QemuExitCode};
+
+// what the handler should report
+#[derive(Clone, Copy)]
+pub struct Expected{
+    pub vector: ExceptionVector,
+    // the decoded error code as the handler prints it, ex: Some(&SelectorErrorCode::new_truncate(0x402))
+    pub error_code: Option<&'static (dyn fmt::Debug + Sync)>,
+}
+
+static EXPECTED: Mutex<Option<Expected>> = Mutex::new(None);
+
+/**
+ * boot, then run trigger with serial output being captured
+ * ex: exception_test::run(boot_info, expected, || unsafe { asm!("ud2") })
+ */
+pub fn run(boot_info: &'static BootInfo, expected: Expected, trigger: fn()) -> ! {
+    serial_print!("Testing {:?} exception\t", expected.vector);
+    unsafe { memory::init_from_boot_info(boot_info) };
+    crate::init();
+    *EXPECTED.lock() = Some(expected);
+    serial::start_capture();
+    trigger();
+    panic!("Execution continued after {:?} exception", expected.vector)
+}
+
+// for the test's #[panic_handler] - passes if the crash report says what was expected
+pub fn panic_handler(info: &PanicInfo) -> ! {
+    serial::stop_capture();
+    let expected = EXPECTED.lock().take();
+    match expected {
+        Some(expected) if reported(&expected) => {
+            serial_println!("[ok]");
+            exit_qemu(QemuExitCode::Success);
+        }
+        _ => {
+            serial_println!("[failed]\n");
+            serial_println!("[Error info: the crash report didn't show the expected exception, {}]\n", info);
+            exit_qemu(QemuExitCode::Failed);
+        }
+    }
+    crate::hlt_loop();
+}
+
+// the lines the exception handler writes, checked against the captured serial output
+fn reported(expected: &Expected) -> bool {
+    let mut vector = Line::new();
+    let _ = writeln!(vector, "EXCEPTION: {:?} (vector {})", expected.vector, expected.vector as u8);
+    let mut error_code = Line::new();
+    if let Some(decoded) = expected.error_code {
+        let _ = write!(error_code, ": {:?}", decoded);
+    }
+    serial::with_captured(|output| output.contains(vector.as_str()) && output.contains(error_code.as_str()))
+}
+
+// formatting without a heap
+struct Line{
+    bytes: [u8; 256],
+    len: usize,
+}
+
+impl Line{
+    fn new() -> Line {
+        Line { bytes: [0; 256], len: 0 }
+    }
+
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or("")
+    }
+}
+
+impl Write for Line{
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        if end > self.bytes.len() {
+            return Err(fmt::Error);
+        }
+        self.bytes[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
@@ -0,0 +1,102 @@
+// qemu's firmware configuration device - how the kernel gets options at boot
+// bootloader 0.9 doesn't pass a command line, so options come in as named files instead
+// ex: qemu ... -fw_cfg name=opt/learning_os/interrupts,string=pic
+use x86_64::instructions::port::Port;
+
+const SELECTOR: u16 = 0x510;
+const DATA: u16 = 0x511;
+
+// items picked through the selector port
+const SIGNATURE: u16 = 0x0000;
+const FILE_DIR: u16 = 0x0019;
+
+// the directory - a big endian count, then one 64 byte entry per file
+const NAME_LEN: usize = 56;
+
+// the prefix qemu keeps for options that aren't its own
+pub const OPTION_PREFIX: &str = "opt/learning_os/";
+
+fn select(item: u16){
+    unsafe { Port::new(SELECTOR).write(item) };
+}
+
+fn read_bytes(buf: &mut [u8]){
+    let mut data = Port::<u8>::new(DATA);
+    for byte in buf {
+        *byte = unsafe { data.read() };
+    }
+}
+
+fn read_u32_be() -> u32 {
+    let mut bytes = [0; 4];
+    read_bytes(&mut bytes);
+    u32::from_be_bytes(bytes)
+}
+
+fn read_u16_be() -> u16 {
+    let mut bytes = [0; 2];
+    read_bytes(&mut bytes);
+    u16::from_be_bytes(bytes)
+}
+
+// false on real hardware and other emulators, nothing answers on the ports there
+pub fn is_present() -> bool {
+    select(SIGNATURE);
+    let mut signature = [0; 4];
+    read_bytes(&mut signature);
+    &signature == b"QEMU"
+}
+
+/**
+ * copy the file called name into buf, returning how many bytes it has
+ * None if there's no such file, a longer file only fills buf
+ */
+pub fn read_file(name: &str, buf: &mut [u8]) -> Option<usize> {
+    if !is_present() {
+        return None;
+    }
+    select(FILE_DIR);
+    let count = read_u32_be();
+    for _ in 0..count {
+        let size = read_u32_be() as usize;
+        let item = read_u16_be();
+        let _reserved = read_u16_be();
+        let mut entry_name = [0; NAME_LEN];
+        read_bytes(&mut entry_name);
+        let len = entry_name.iter().position(|&byte| byte == 0).unwrap_or(NAME_LEN);
+        if &entry_name[..len] == name.as_bytes() {
+            let size = size.min(buf.len());
+            select(item);
+            read_bytes(&mut buf[..size]);
+            return Some(size);
+        }
+    }
+    None
+}
+
+/**
+ * a boot option given with -fw_cfg name=opt/learning_os/<name>,string=<value>
+ * ex: boot_option("interrupts", &mut buf) == Some("pic")
+ */
+pub fn boot_option<'a>(name: &str, buf: &'a mut [u8]) -> Option<&'a str> {
+    let mut path = [0; NAME_LEN];
+    let path_len = OPTION_PREFIX.len() + name.len();
+    if path_len > NAME_LEN {
+        return None;
+    }
+    path[..OPTION_PREFIX.len()].copy_from_slice(OPTION_PREFIX.as_bytes());
+    path[OPTION_PREFIX.len()..path_len].copy_from_slice(name.as_bytes());
+    let path = core::str::from_utf8(&path[..path_len]).ok()?;
+    let len = read_file(path, buf)?;
+    core::str::from_utf8(&buf[..len])
+        .ok()
+        .map(|value| value.trim_matches(|c: char| c == '\0' || c.is_whitespace()))
+}
+
+#[test_case]
+fn test_fw_cfg_is_found(){
+    // the tests always run in qemu
+    assert!(is_present());
+    let mut buf = [0; 16];
+    assert_eq!(read_file("opt/learning_os/no such option", &mut buf), None);
+}
@@ -5,11 +5,14 @@
  * We need one to switch stacks to stop a fatal
  * Triple fault from happening when the stack ptr
  * is stuck on the guard page after stack overflow
+ *
+ * The IST stacks come from memory::stack, so they have guard pages too
+ * memory has to be initialized before init is called
  */
 
  //creating the tss
- use x86_64::VirtAddr;
  use x86_64::structures::tss::TaskStateSegment;
+ use crate::memory;
  use lazy_static::lazy_static;
  //creating the gdt
  use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor};
@@ -22,12 +25,11 @@
         let mut tss = TaskStateSegment::new();
         // defining the IST entry for double fault
         tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-            // static muts are susceptible to race conditions hence the unsafe
-            let stack_start = VirtAddr::from_ptr(unsafe{&STACK});
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
+            const STACK_PAGES: u64 = 5;
+            let stack = memory::stack::allocate("double fault stack", STACK_PAGES)
+                .expect("double fault stack allocation failed");
+            // stacks grow down, so the cpu starts at the top
+            stack.top()
         };
         tss
     };
@@ -1,13 +1,18 @@
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
-use crate::println;
 use lazy_static::lazy_static;
-use crate::gdt;
 // replicates secondary pic slaved to pin 2 on primary pic
 use pic8259::ChainedPics;
 use spin;
-use crate::print;
-use x86_64::structures::idt::PageFaultErrorCode;
-use crate::hlt_loop;
+use crate::serial_println;
+
+// cpu exceptions - divide error, page fault, double fault, etc
+pub mod exceptions;
+// local and I/O APIC, used instead of the PICs when the machine has them
+pub mod apic;
+// register_irq and unregister_irq, for drivers
+pub mod irq;
+// per vector interrupt counters
+pub mod stats;
 
 // IDT must live for program runtime - cpu will reference it a lot
 // has to be static but also mutable so that we can set the 
@@ -17,18 +22,11 @@ use crate::hlt_loop;
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        unsafe{
-            idt.double_fault.set_handler_fn(double_fault_handler)
-            // point to the gdt index
-            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
-        }
-        // indexing starts since we're dealing with non-cpu interrupts > 31
-        idt[InterruptIndex::Timer.as_usize()]
-            .set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()]
-            .set_handler_fn(keyboard_interrupt_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
+        exceptions::set_handlers(&mut idt);
+        // non-cpu interrupts start at 32 - see irq::register_irq
+        irq::set_handlers(&mut idt);
+        idt[apic::SPURIOUS_VECTOR as usize]
+            .set_handler_fn(apic_spurious_interrupt_handler);
 
         idt
     };
@@ -38,76 +36,180 @@ pub fn init_idt(){
     IDT.load();
 }
 
+// which chip hardware interrupts come through
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptController{
+    Apic,
+    // the legacy 8259 pair
+    Pic,
+}
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame){
-    println!("Caught a breakpoint exception\n{:#?}", stack_frame);
+// the names the interrupts boot option takes - see lib::init
+impl core::str::FromStr for InterruptController{
+    type Err = ();
+
+    fn from_str(name: &str) -> Result<InterruptController, ()> {
+        match name {
+            "apic" => Ok(InterruptController::Apic),
+            "pic" | "8259" => Ok(InterruptController::Pic),
+            _ => Err(()),
+        }
+    }
 }
 
-//x86 architecture doesn't allow returning from a double_fault exception
-extern "x86-interrupt" fn double_fault_handler(stack_frame: InterruptStackFrame, _error_code: u64) -> !{
-    panic!("Caught a double fault exception \n{:#?}", stack_frame);
+/**
+ * set up the preferred interrupt controller, falling back to the PICs if there's no APIC
+ * then registers the keyboard handler - the timer belongs to time::init
+ * returns the one that ended up being used
+ * irq lines keep the same vectors either way, and stay masked until something registers for them
+ * # Safety
+ * interrupts have to be disabled, and this should only run once
+ */
+pub unsafe fn init_controller(preferred: InterruptController) -> InterruptController {
+    // remap the PICs even when they'll be masked, so a spurious irq from them
+    // lands on one of our vectors instead of looking like a cpu exception
+    PICS.lock().initialize();
+    PICS.lock().write_masks(0xff, 0xff);
+    let mut controller = InterruptController::Pic;
+    if preferred == InterruptController::Apic {
+        match apic::init() {
+            Ok(()) => controller = InterruptController::Apic,
+            Err(err) => {
+                serial_println!("no APIC ({:?}), using the 8259 PICs", err);
+            }
+        }
+    }
+    irq::register_irq(InterruptIndex::Keyboard.irq(), keyboard_interrupt_handler)
+        .expect("keyboard irq registration failed");
+    controller
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame){
-    print!(".");
-    unsafe{
-        // send the EOI signal so we can continue to process other signals
-        PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+pub fn interrupt_controller() -> InterruptController {
+    if apic::is_enabled() {
+        InterruptController::Apic
+    } else {
+        InterruptController::Pic
     }
 }
 
-extern "x86-interrupt" fn page_fault_handler(stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode){
-    // Cr2 holds address where error takes place
-    use x86_64::registers::control::Cr2;
-    println!("EXCEPTION: Caught a page_fault");
-    println!("Invalid Address Access: {:?}", Cr2::read());
-    // info on what type of memory access caused the page fault
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    // resolve the page fault by looping
-    hlt_loop();
+// send the EOI signal so we can continue to process other signals
+fn end_of_interrupt(irq: u8){
+    if apic::is_enabled() {
+        apic::end_of_interrupt();
+    } else {
+        unsafe { PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET + irq) };
+    }
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame){
-    use x86_64::instructions::port::Port;
-    use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
-    use spin::Mutex;
-   lazy_static!{
-    // Us104Key - standard keyboard, scancodes, ignore the ctrl key for now
-    static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> = 
-        Mutex::new(Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore));
-   }
-   // create a reference to the locked keyboard object
-   let mut keyboard = KEYBOARD.lock();
-   let mut keyboard_port = Port::new(0x60);
-   // read the scancode from the hardware port attached to the keyboard
-   let scancode: u8 = unsafe{keyboard_port.read()};
-   // bind the scancode to the keyboard if its there
-   if let Ok(Some(key_event)) = keyboard.add_byte(scancode){
-    // if there's a scancode, process its data...is it a press or release, and the key
-    if let Some(key) = keyboard.process_keyevent(key_event){
-        match key {
-            // if we have a readable character, print it, etc
-            DecodedKey::Unicode(readable_character) => print!("{}", readable_character),
-            DecodedKey::RawKey(character) => print!("{:?}", character)
+// let irq through to the cpu - on the PICs the secondary only gets through its cascade line
+fn unmask_irq(irq: u8){
+    if irq >= irq::ISA_IRQ_LINES {
+        apic::route_gsi(irq as u32, PIC_1_OFFSET + irq);
+    } else if apic::is_enabled() {
+        apic::route_isa_irq(irq, PIC_1_OFFSET + irq);
+    } else {
+        let mut pics = PICS.lock();
+        let [mut primary, mut secondary] = unsafe { pics.read_masks() };
+        if irq < 8 {
+            primary &= !(1 << irq);
+        } else {
+            secondary &= !(1 << (irq - 8));
+            primary &= !(1 << CASCADE_IRQ);
         }
+        unsafe { pics.write_masks(primary, secondary) };
     }
-   }
+}
+
+fn mask_irq(irq: u8){
+    if irq >= irq::ISA_IRQ_LINES {
+        apic::mask_gsi(irq as u32);
+    } else if apic::is_enabled() {
+        apic::mask_isa_irq(irq);
+    } else {
+        let mut pics = PICS.lock();
+        let [mut primary, mut secondary] = unsafe { pics.read_masks() };
+        if irq < 8 {
+            primary |= 1 << irq;
+        } else {
+            secondary |= 1 << (irq - 8);
+        }
+        unsafe { pics.write_masks(primary, secondary) };
+    }
+}
+
+// reading a PIC's command port after this OCW3 gives its in-service register
+const PIC_READ_ISR: u8 = 0x0b;
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_2_COMMAND: u16 = 0xa0;
+const PIC_EOI: u8 = 0x20;
+
+// bit n set means irq n (n + 8 on the secondary) is being serviced
+fn pic_in_service(command_port: u16) -> u8 {
+    use x86_64::instructions::port::Port;
+    let mut port = Port::new(command_port);
     unsafe {
-        PICS.lock().notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+        port.write(PIC_READ_ISR);
+        port.read()
     }
 }
 
-#[test_case]
-fn test_breakpoint_exception(){
-    // invoke a breakpoint exception
-    x86_64::instructions::interrupts::int3();
-    init_idt();
+/**
+ * a PIC raises its lowest priority line - irq 7, or 15 on the secondary - when an
+ * interrupt goes away before the cpu acknowledges it
+ * those have to be skipped, and must not get an EOI since nothing is in service
+ * a spurious 15 did go through the primary's cascade line though, so the primary still needs one
+ * with the APIC the PICs are masked but can still send these, so with nothing
+ * registered on the line, 7 and 15 can only be spurious
+ * true if the irq was spurious and has been dealt with
+ */
+fn handle_spurious_irq(irq: u8, listening: bool) -> bool {
+    if irq != 7 && irq != 15 {
+        return false;
+    }
+    let spurious = if apic::is_enabled() {
+        !listening
+    } else {
+        // hold the lock so nothing else talks to the PICs in between
+        let _pics = PICS.lock();
+        let in_service = if irq == 7 { pic_in_service(PIC_1_COMMAND) } else { pic_in_service(PIC_2_COMMAND) };
+        let spurious = in_service & 0x80 == 0;
+        if spurious && irq == 15 {
+            use x86_64::instructions::port::Port;
+            unsafe { Port::new(PIC_1_COMMAND).write(PIC_EOI) };
+        }
+        spurious
+    };
+    if spurious {
+        stats::record_spurious();
+    }
+    spurious
+}
+
+// only queues the scancode, task::keyboard decodes it outside the interrupt
+fn keyboard_interrupt_handler(_irq: u8){
+    // read the scancode from the hardware port attached to the keyboard
+    let scancode = match crate::ps2::read_data() {
+        Some(scancode) => scancode,
+        None => return,
+    };
+    // or an answer to a command the ps2 driver is waiting on
+    if crate::ps2::take_response(crate::ps2::Ps2Port::First, scancode) {
+        return;
+    }
+    crate::task::keyboard::add_scancode(scancode);
+}
+
+// nothing to do but count it, and the local APIC doesn't want an EOI for these
+extern "x86-interrupt" fn apic_spurious_interrupt_handler(_stack_frame: InterruptStackFrame){
+    stats::record(apic::SPURIOUS_VECTOR);
+    stats::record_spurious();
 }
 
 // timer uses first index of pic
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+// the secondary PIC is wired to this line of the primary
+const CASCADE_IRQ: u8 = 2;
 pub static PICS: spin::Mutex<ChainedPics> = spin::Mutex::new(unsafe {ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET)});
 
 
@@ -123,8 +225,35 @@ impl InterruptIndex{
         self as u8
     }
 
-    fn as_usize(self) -> usize {
-        usize::from(self.as_u8())
+    // ISA irq line - the same number on the PICs and before any I/O APIC override
+    fn irq(self) -> u8 {
+        self.as_u8() - PIC_1_OFFSET
     }
 }
 
+#[test_case]
+fn test_interrupt_controller_names(){
+    assert_eq!("pic".parse(), Ok(InterruptController::Pic));
+    assert_eq!("apic".parse(), Ok(InterruptController::Apic));
+    assert_eq!("x2apic".parse::<InterruptController>(), Err(()));
+}
+
+#[test_case]
+fn test_timer_interrupt_arrives(){
+    // hlt only returns once an interrupt comes in, the timer is the one we're sure of
+    x86_64::instructions::hlt();
+}
+
+#[test_case]
+fn test_apic_is_used_when_present(){
+    // qemu always emulates a local and an I/O APIC
+    assert_eq!(interrupt_controller(), InterruptController::Apic);
+}
+
+#[test_case]
+fn test_unused_irq_7_is_spurious(){
+    // nothing is registered on line 7, so with the APIC this can only be the PIC's spurious irq
+    let before = stats::spurious_count();
+    unsafe { core::arch::asm!("int 39") };
+    assert_eq!(stats::spurious_count(), before + 1);
+}
@@ -0,0 +1,220 @@
+// the APIC interrupt controllers that replaced the 8259 PICs
+// local APIC - one per cpu, takes interrupts in and needs the end of interrupt signal
+// I/O APIC - takes the device irq lines and sends each to a vector on some local APIC
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::registers::model_specific::Msr;
+use crate::{acpi, memory};
+
+// local APIC register offsets
+const LAPIC_ID: u64 = 0x20;
+const LAPIC_TASK_PRIORITY: u64 = 0x80;
+const LAPIC_EOI: u64 = 0xb0;
+const LAPIC_SPURIOUS: u64 = 0xf0;
+// bit 8 of the spurious interrupt register turns the local APIC on
+const LAPIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+// local vector table entries for the two local interrupt pins
+const LAPIC_LVT_LINT0: u64 = 0x350;
+const LAPIC_LVT_LINT1: u64 = 0x360;
+// bits of an LVT entry
+const LVT_DELIVERY_NMI: u32 = 0b100 << 8;
+const LVT_ACTIVE_LOW: u32 = 1 << 13;
+const LVT_MASKED: u32 = 1 << 16;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_GLOBAL_ENABLE: u64 = 1 << 11;
+
+// the local APIC sends this when an interrupt goes away before the cpu takes it - no EOI needed
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+// I/O APIC registers are reached by writing the index to IOREGSEL and then using IOWIN
+const IOAPIC_REGSEL: u64 = 0x00;
+const IOAPIC_WINDOW: u64 = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+// bits of the low half of a redirection entry
+const REDIRECTION_ACTIVE_LOW: u32 = 1 << 13;
+const REDIRECTION_LEVEL_TRIGGERED: u32 = 1 << 15;
+const REDIRECTION_MASKED: u32 = 1 << 16;
+
+// where the registers are mapped, 0 until init
+static LOCAL_APIC: AtomicU64 = AtomicU64::new(0);
+static IO_APIC: AtomicU64 = AtomicU64::new(0);
+// first GSI the I/O APIC handles and how many it has
+static IO_APIC_GSI_BASE: AtomicU64 = AtomicU64::new(0);
+static IO_APIC_INPUTS: AtomicU64 = AtomicU64::new(0);
+// kept for the interrupt source overrides, so irqs can be routed after init
+static MADT: Mutex<Option<acpi::Madt>> = Mutex::new(None);
+
+// cpuid leaf 1, edx bit 9
+// __cpuid is only unsafe on older toolchains
+#[allow(unused_unsafe)]
+pub fn is_present() -> bool {
+    let cpuid = unsafe { core::arch::x86_64::__cpuid(1) };
+    cpuid.edx & (1 << 9) != 0
+}
+
+// true once init has switched interrupt delivery over to the APICs
+pub fn is_enabled() -> bool {
+    LOCAL_APIC.load(Ordering::SeqCst) != 0
+}
+
+unsafe fn read(base: &AtomicU64, offset: u64) -> u32 {
+    ptr::read_volatile((base.load(Ordering::SeqCst) + offset) as *const u32)
+}
+
+unsafe fn write(base: &AtomicU64, offset: u64, value: u32){
+    ptr::write_volatile((base.load(Ordering::SeqCst) + offset) as *mut u32, value)
+}
+
+unsafe fn io_apic_read(register: u32) -> u32 {
+    write(&IO_APIC, IOAPIC_REGSEL, register);
+    read(&IO_APIC, IOAPIC_WINDOW)
+}
+
+unsafe fn io_apic_write(register: u32, value: u32){
+    write(&IO_APIC, IOAPIC_REGSEL, register);
+    write(&IO_APIC, IOAPIC_WINDOW, value);
+}
+
+#[derive(Debug)]
+pub enum ApicError{
+    // cpuid says there's no local APIC
+    NotPresent,
+    // no ACPI MADT, or it doesn't list an I/O APIC
+    NoIoApic,
+    MapFailed,
+}
+
+/**
+ * map and enable the local APIC and the first I/O APIC, with every I/O APIC input masked
+ * the 8259s have to be masked by the caller - they'd still raise interrupts otherwise
+ * # Safety
+ * interrupts should be disabled, and this should only run once
+ */
+pub unsafe fn init() -> Result<(), ApicError> {
+    if !is_present() {
+        return Err(ApicError::NotPresent);
+    }
+    let madt = acpi::madt().ok_or(ApicError::NoIoApic)?;
+    let io_apic = *madt.io_apics().next().ok_or(ApicError::NoIoApic)?;
+
+    // the firmware may have left the local APIC turned off
+    let mut apic_base = Msr::new(IA32_APIC_BASE);
+    apic_base.write(apic_base.read() | APIC_BASE_GLOBAL_ENABLE);
+
+    let local_apic = memory::mmio::map(madt.local_apic_address, 0x1000).map_err(|_| ApicError::MapFailed)?;
+    let io_apic_registers = memory::mmio::map(io_apic.address, 0x20).map_err(|_| ApicError::MapFailed)?;
+    IO_APIC.store(io_apic_registers.as_u64(), Ordering::SeqCst);
+    IO_APIC_GSI_BASE.store(io_apic.gsi_base as u64, Ordering::SeqCst);
+    // bits 16-23 of the version register are the index of the last redirection entry
+    let inputs = ((io_apic_read(IOAPIC_VERSION) >> 16) & 0xff) + 1;
+    IO_APIC_INPUTS.store(inputs as u64, Ordering::SeqCst);
+    for input in 0..inputs {
+        io_apic_write(IOAPIC_REDIRECTION_TABLE + input * 2, REDIRECTION_MASKED);
+    }
+
+    LOCAL_APIC.store(local_apic.as_u64(), Ordering::SeqCst);
+    // accept every priority, then switch it on
+    write(&LOCAL_APIC, LAPIC_TASK_PRIORITY, 0);
+    write(&LOCAL_APIC, LAPIC_SPURIOUS, LAPIC_SOFTWARE_ENABLE | SPURIOUS_VECTOR as u32);
+    set_local_interrupt_pins(&madt);
+    *MADT.lock() = Some(madt);
+    Ok(())
+}
+
+/**
+ * don't trust what the firmware left in LINT0 and LINT1
+ * LINT0 is the 8259s' ExtINT line, masked since the 8259s are
+ * the MADT says which pin is NMI - LINT1 on a pc if it doesn't say
+ * only one cpu, so every NMI entry applies to it
+ */
+unsafe fn set_local_interrupt_pins(madt: &acpi::Madt){
+    let mut lint = [LVT_MASKED, LVT_DELIVERY_NMI];
+    let mut nmis = madt.local_apic_nmis().peekable();
+    if nmis.peek().is_some() {
+        lint = [LVT_MASKED; 2];
+    }
+    for nmi in nmis {
+        if let Some(entry) = lint.get_mut(nmi.lint as usize) {
+            // NMIs are always edge triggered, only the polarity can change
+            *entry = LVT_DELIVERY_NMI;
+            if nmi.flags & 0b11 == 0b11 {
+                *entry |= LVT_ACTIVE_LOW;
+            }
+        }
+    }
+    write(&LOCAL_APIC, LAPIC_LVT_LINT0, lint[0]);
+    write(&LOCAL_APIC, LAPIC_LVT_LINT1, lint[1]);
+}
+
+// the id the I/O APIC uses to send interrupts to this cpu
+pub fn local_apic_id() -> u8 {
+    unsafe { (read(&LOCAL_APIC, LAPIC_ID) >> 24) as u8 }
+}
+
+pub fn end_of_interrupt(){
+    unsafe { write(&LOCAL_APIC, LAPIC_EOI, 0) };
+}
+
+// which I/O APIC input an ISA irq arrives on, and its MPS INTI flags
+fn isa_irq_source(irq: u8) -> (u32, u16) {
+    let madt = MADT.lock();
+    let source = madt.as_ref().expect("APIC not initialized")
+        .overrides()
+        .find(|entry| entry.irq == irq)
+        .map(|entry| (entry.gsi, entry.flags));
+    source.unwrap_or((irq as u32, 0))
+}
+
+/**
+ * send an ISA irq to vector on this cpu
+ * the MADT's overrides decide which I/O APIC input it arrives on and how it's triggered,
+ * without one ISA irqs are edge triggered, active high and keep their number
+ */
+pub fn route_isa_irq(irq: u8, vector: u8){
+    let (gsi, flags) = isa_irq_source(irq);
+    let mut low = vector as u32;
+    // 0b11 is active low / level triggered, 0b00 means whatever the bus normally does
+    if flags & 0b11 == 0b11 {
+        low |= REDIRECTION_ACTIVE_LOW;
+    }
+    if (flags >> 2) & 0b11 == 0b11 {
+        low |= REDIRECTION_LEVEL_TRIGGERED;
+    }
+    set_redirection(gsi, low, local_apic_id());
+}
+
+// stop an ISA irq from reaching any cpu
+pub fn mask_isa_irq(irq: u8){
+    let (gsi, _) = isa_irq_source(irq);
+    set_redirection(gsi, REDIRECTION_MASKED, 0);
+}
+
+fn set_redirection(gsi: u32, low: u32, destination: u8){
+    let input = gsi as u64 - IO_APIC_GSI_BASE.load(Ordering::SeqCst);
+    assert!(input < IO_APIC_INPUTS.load(Ordering::SeqCst), "GSI {} isn't on the I/O APIC", gsi);
+    let register = IOAPIC_REDIRECTION_TABLE + input as u32 * 2;
+    unsafe {
+        // mask while changing it so a half written entry never fires
+        io_apic_write(register, REDIRECTION_MASKED);
+        io_apic_write(register + 1, (destination as u32) << 24);
+        io_apic_write(register, low);
+    }
+}
+
+// false without the APIC, or if the I/O APIC has fewer inputs
+pub fn has_gsi(gsi: u32) -> bool {
+    let base = IO_APIC_GSI_BASE.load(Ordering::SeqCst);
+    is_enabled() && gsi as u64 >= base && (gsi as u64) < base + IO_APIC_INPUTS.load(Ordering::SeqCst)
+}
+
+// send a GSI that isn't an ISA irq to vector on this cpu, edge triggered and active high
+pub fn route_gsi(gsi: u32, vector: u8){
+    set_redirection(gsi, vector as u32, local_apic_id());
+}
+
+pub fn mask_gsi(gsi: u32){
+    set_redirection(gsi, REDIRECTION_MASKED, 0);
+}
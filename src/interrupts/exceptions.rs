@@ -0,0 +1,279 @@
+// handlers for the cpu exceptions - vectors 0 to 31 of the IDT
+// without a handler an exception escalates to a double fault and the original cause is lost
+use core::fmt;
+use core::sync::atomic::{AtomicU8, Ordering};
+use x86_64::structures::idt::{
+    ExceptionVector, InterruptDescriptorTable, InterruptStackFrameValue, PageFaultErrorCode, SelectorErrorCode,
+};
+use x86_64::registers::mxcsr::{self, MxCsr};
+use x86_64::VirtAddr;
+use crate::{crash, gdt, memory};
+
+// no exception has happened yet - not a valid exception vector
+const NO_EXCEPTION: u8 = u8::MAX;
+static LAST_EXCEPTION: AtomicU8 = AtomicU8::new(NO_EXCEPTION);
+
+// vector of the most recent exception, ex: for tests to check the right handler ran
+pub fn last_exception() -> Option<u8> {
+    match LAST_EXCEPTION.load(Ordering::SeqCst) {
+        NO_EXCEPTION => None,
+        vector => Some(vector),
+    }
+}
+
+/**
+ * general purpose registers of the interrupted code, saved by the exception stubs
+ * in the order they end up on the stack - r15 was pushed last so it's first
+ */
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct Registers{
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+}
+
+/**
+ * everything on the stack when a stub calls exception_dispatch
+ * the stub pushes a 0 error code for exceptions that don't have one, so the layout is always the same
+ */
+#[repr(C)]
+pub struct ExceptionContext{
+    pub registers: Registers,
+    pub error_code: u64,
+    pub stack_frame: InterruptStackFrameValue,
+}
+
+/**
+ * the x86-interrupt calling convention doesn't let handlers see the interrupted registers,
+ * so every exception goes through a small assembly stub instead
+ * the stub saves the registers, calls exception_dispatch with them and the vector,
+ * then restores them and returns with iretq if exception_dispatch returns
+ * stack on entry to dispatch: 5 frame words + error code + 15 registers, plus 8 bytes of padding,
+ * which keeps rsp 16 byte aligned at the call like the ABI expects
+ */
+macro_rules! exception_stub {
+    ($name:ident, $vector:literal) => {
+        exception_stub!(@stub $name, $vector, "push 0");
+    };
+    ($name:ident, $vector:literal, error_code) => {
+        exception_stub!(@stub $name, $vector, "");
+    };
+    (@stub $name:ident, $vector:literal, $push_error_code:literal) => {
+        core::arch::global_asm!(
+            concat!(
+                ".pushsection .text\n",
+                ".global ", stringify!($name), "\n",
+                stringify!($name), ":\n",
+                $push_error_code, "\n",
+                "push rax\n push rbx\n push rcx\n push rdx\n push rsi\n push rdi\n push rbp\n",
+                "push r8\n push r9\n push r10\n push r11\n push r12\n push r13\n push r14\n push r15\n",
+                "mov rdi, rsp\n",
+                "mov esi, ", stringify!($vector), "\n",
+                "sub rsp, 8\n",
+                "cld\n",
+                "call {dispatch}\n",
+                "add rsp, 8\n",
+                "pop r15\n pop r14\n pop r13\n pop r12\n pop r11\n pop r10\n pop r9\n pop r8\n",
+                "pop rbp\n pop rdi\n pop rsi\n pop rdx\n pop rcx\n pop rbx\n pop rax\n",
+                // drop the error code
+                "add rsp, 8\n",
+                "iretq\n",
+                ".popsection\n",
+            ),
+            dispatch = sym exception_dispatch,
+        );
+        extern "C" {
+            fn $name();
+        }
+    };
+}
+
+exception_stub!(divide_error_stub, 0);
+exception_stub!(debug_stub, 1);
+exception_stub!(non_maskable_interrupt_stub, 2);
+exception_stub!(breakpoint_stub, 3);
+exception_stub!(overflow_stub, 4);
+exception_stub!(bound_range_exceeded_stub, 5);
+exception_stub!(invalid_opcode_stub, 6);
+exception_stub!(device_not_available_stub, 7);
+exception_stub!(double_fault_stub, 8, error_code);
+exception_stub!(invalid_tss_stub, 10, error_code);
+exception_stub!(segment_not_present_stub, 11, error_code);
+exception_stub!(stack_segment_fault_stub, 12, error_code);
+exception_stub!(general_protection_fault_stub, 13, error_code);
+exception_stub!(page_fault_stub, 14, error_code);
+exception_stub!(x87_floating_point_stub, 16);
+exception_stub!(alignment_check_stub, 17, error_code);
+exception_stub!(machine_check_stub, 18);
+exception_stub!(simd_floating_point_stub, 19);
+exception_stub!(virtualization_stub, 20);
+exception_stub!(security_exception_stub, 30, error_code);
+
+fn stub_addr(stub: unsafe extern "C" fn()) -> VirtAddr {
+    VirtAddr::new(stub as usize as u64)
+}
+
+pub(super) fn set_handlers(idt: &mut InterruptDescriptorTable){
+    // unsafe - each stub has to match what the cpu pushes for its vector
+    unsafe{
+        idt.divide_error.set_handler_addr(stub_addr(divide_error_stub));
+        idt.debug.set_handler_addr(stub_addr(debug_stub));
+        idt.non_maskable_interrupt.set_handler_addr(stub_addr(non_maskable_interrupt_stub));
+        idt.breakpoint.set_handler_addr(stub_addr(breakpoint_stub));
+        idt.overflow.set_handler_addr(stub_addr(overflow_stub));
+        idt.bound_range_exceeded.set_handler_addr(stub_addr(bound_range_exceeded_stub));
+        idt.invalid_opcode.set_handler_addr(stub_addr(invalid_opcode_stub));
+        idt.device_not_available.set_handler_addr(stub_addr(device_not_available_stub));
+        idt.double_fault.set_handler_addr(stub_addr(double_fault_stub))
+            // point to the gdt index
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        idt.invalid_tss.set_handler_addr(stub_addr(invalid_tss_stub));
+        idt.segment_not_present.set_handler_addr(stub_addr(segment_not_present_stub));
+        idt.stack_segment_fault.set_handler_addr(stub_addr(stack_segment_fault_stub));
+        idt.general_protection_fault.set_handler_addr(stub_addr(general_protection_fault_stub));
+        idt.page_fault.set_handler_addr(stub_addr(page_fault_stub));
+        idt.x87_floating_point.set_handler_addr(stub_addr(x87_floating_point_stub));
+        idt.alignment_check.set_handler_addr(stub_addr(alignment_check_stub));
+        idt.machine_check.set_handler_addr(stub_addr(machine_check_stub));
+        idt.simd_floating_point.set_handler_addr(stub_addr(simd_floating_point_stub));
+        idt.virtualization.set_handler_addr(stub_addr(virtualization_stub));
+        idt.security_exception.set_handler_addr(stub_addr(security_exception_stub));
+    }
+}
+
+/**
+ * every exception stub lands here
+ * returning resumes the interrupted code - only traps and resolved page faults do that,
+ * faults would just re-run the faulting instruction
+ */
+extern "C" fn exception_dispatch(context: &mut ExceptionContext, vector: u8){
+    LAST_EXCEPTION.store(vector, Ordering::SeqCst);
+    super::stats::record(vector);
+    let error_code = context.error_code;
+    match vector {
+        // traps - the instruction already finished, so execution can carry on
+        1 => report_trap(ExceptionVector::Debug, context),
+        2 => report_trap(ExceptionVector::NonMaskableInterrupt, context),
+        3 => report_trap(ExceptionVector::Breakpoint, context),
+        4 => report_trap(ExceptionVector::Overflow, context),
+        0 => fatal_exception(ExceptionVector::Division, context, None),
+        5 => fatal_exception(ExceptionVector::BoundRange, context, None),
+        6 => fatal_exception(ExceptionVector::InvalidOpcode, context, None),
+        // an x87 instruction ran while the fpu is disabled or CR0.TS is set
+        7 => fatal_exception(ExceptionVector::DeviceNotAvailable, context, None),
+        //x86 architecture doesn't allow returning from a double_fault exception
+        8 => double_fault(context),
+        // ExceptionVector names the vector, SelectorErrorCode decodes the #TS, #NP, #SS and #GP error codes
+        10 => fatal_exception(ExceptionVector::InvalidTss, context, Some(&SelectorErrorCode::new_truncate(error_code))),
+        11 => fatal_exception(ExceptionVector::SegmentNotPresent, context, Some(&SelectorErrorCode::new_truncate(error_code))),
+        12 => fatal_exception(ExceptionVector::Stack, context, Some(&SelectorErrorCode::new_truncate(error_code))),
+        13 => fatal_exception(ExceptionVector::GeneralProtection, context, Some(&SelectorErrorCode::new_truncate(error_code))),
+        14 => page_fault(context),
+        16 => fatal_exception(ExceptionVector::X87FloatingPoint, context, None),
+        // the error code is always 0, only raised for user mode code
+        17 => fatal_exception(ExceptionVector::AlignmentCheck, context, Some(&error_code)),
+        // the hardware found an internal error, nothing to recover
+        18 => fatal_exception(ExceptionVector::MachineCheck, context, None),
+        // no error code, MXCSR says which of the unmasked exceptions happened
+        19 => fatal_exception(ExceptionVector::SimdFloatingPoint, context, Some(&simd_exceptions())),
+        20 => fatal_exception(ExceptionVector::Virtualization, context, None),
+        30 => fatal_exception(ExceptionVector::Security, context, Some(&error_code)),
+        _ => panic!("exception stub for unhandled vector {}", vector),
+    }
+}
+
+/**
+ * the exception flags in MXCSR that aren't masked - the ones that raised the exception
+ * ex: MxCsr(DIVIDE_BY_ZERO)
+ */
+fn simd_exceptions() -> MxCsr {
+    let mxcsr = mxcsr::read().bits();
+    // the mask bits sit 7 above the flags they mask
+    MxCsr::from_bits_truncate(mxcsr & !(mxcsr >> 7) & 0x3f)
+}
+
+/**
+ * print what the cpu told us to serial, so test logs have it
+ * a trap can land in the middle of a print, so it's skipped when serial is busy
+ * and never goes to the screen, whose lock can't be checked the same way
+ */
+fn report_trap(vector: ExceptionVector, context: &ExceptionContext){
+    crate::serial::try_print(format_args!(
+        "EXCEPTION: {:?} (vector {})\n{:#?}\n", vector, vector as u8, context.stack_frame));
+}
+
+// full crash report with the interrupted registers and a backtrace, then panic
+fn fatal_exception(vector: ExceptionVector, context: &ExceptionContext, error_code: Option<&dyn fmt::Debug>) -> !{
+    crash::report_exception(vector, context, error_code);
+    panic!("unrecoverable {:?} exception (vector {})", vector, vector as u8);
+}
+
+fn double_fault(context: &ExceptionContext) -> !{
+    use x86_64::registers::control::Cr2;
+    // overflowing a stack faults on its guard page, then faults again pushing the
+    // page fault's frame onto that same stack - which ends up here with Cr2 in the guard page
+    if let Some(stack_name) = memory::stack::guard_page_owner(Cr2::read()){
+        crash::report_exception(ExceptionVector::Double, context, None);
+        panic!("stack overflow in {}", stack_name);
+    }
+    fatal_exception(ExceptionVector::Double, context, None);
+}
+
+fn page_fault(context: &ExceptionContext){
+    // Cr2 holds address where error takes place
+    use x86_64::registers::control::Cr2;
+    // info on what type of memory access caused the page fault
+    let error_code = PageFaultErrorCode::from_bits_truncate(context.error_code);
+    // first touch of a lazy region - the page is mapped now, so returning retries the access
+    if memory::lazy::handle_page_fault(Cr2::read(), error_code){
+        return;
+    }
+    if let Some(stack_name) = memory::stack::guard_page_owner(Cr2::read()){
+        crash::report_exception(ExceptionVector::Page, context, Some(&error_code));
+        panic!("stack overflow in {}", stack_name);
+    }
+    fatal_exception(ExceptionVector::Page, context, Some(&error_code));
+}
+
+#[test_case]
+fn test_breakpoint_exception(){
+    // invoke a breakpoint exception
+    x86_64::instructions::interrupts::int3();
+    assert_eq!(last_exception(), Some(ExceptionVector::Breakpoint as u8));
+}
+
+#[test_case]
+fn test_overflow_exception_returns(){
+    // INTO doesn't exist in 64 bit mode, but the vector can still be raised by number
+    unsafe { core::arch::asm!("int 4") };
+    assert_eq!(last_exception(), Some(ExceptionVector::Overflow as u8));
+}
+
+#[test_case]
+fn test_registers_survive_a_trap(){
+    // the stub has to put back every register it saved
+    let value: u64;
+    unsafe {
+        core::arch::asm!(
+            "mov r12, 0x1234",
+            "int3",
+            "mov {}, r12",
+            out(reg) value,
+            out("r12") _,
+        );
+    }
+    assert_eq!(value, 0x1234);
+}
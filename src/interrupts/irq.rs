@@ -0,0 +1,165 @@
+// hardware interrupt lines and the drivers listening on them
+// every line goes through the same handler, which calls whatever is registered
+// for it and then sends the end of interrupt, so drivers never touch the PICs or APIC
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::set_general_handler;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use super::PIC_1_OFFSET;
+
+// the legacy ISA lines, vectors PIC_1_OFFSET to PIC_1_OFFSET + 15 with either controller
+pub const ISA_IRQ_LINES: u8 = 16;
+/**
+ * lines past the ISA ones are I/O APIC inputs with no PIC equivalent, ex: HPET timers
+ * they only exist with the APIC, and are set up edge triggered and active high
+ */
+pub const IRQ_LINES: u8 = 24;
+// drivers that can share one line, ex: a PCI card and a serial port
+const MAX_HANDLERS_PER_LINE: usize = 4;
+
+/**
+ * runs in interrupt context with interrupts disabled - keep it short
+ * gets the line number, so one function can serve several lines
+ * on a shared line every handler runs, each has to check whether its device raised it
+ */
+pub type IrqHandler = fn(irq: u8);
+
+// what unregister_irq needs to find a handler again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqHandlerId{
+    irq: u8,
+    slot: usize,
+    // the slot's generation when this handler got it, see Slot
+    generation: u64,
+}
+
+impl IrqHandlerId{
+    pub fn irq(&self) -> u8 {
+        self.irq
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError{
+    // not one of the IRQ_LINES lines, or one the interrupt controller in use doesn't have
+    InvalidIrq,
+    // MAX_HANDLERS_PER_LINE handlers already share the line
+    LineFull,
+}
+
+#[derive(Clone, Copy)]
+struct Slot{
+    handler: Option<IrqHandler>,
+    // bumped every time the slot is handed out, so an id for a handler that's
+    // already gone can't unregister whoever got the slot next
+    generation: u64,
+}
+
+const EMPTY_SLOT: Slot = Slot { handler: None, generation: 0 };
+
+/**
+ * only locked with interrupts disabled, otherwise an irq arriving while
+ * register_irq holds the lock would spin forever in irq_handler
+ */
+static HANDLERS: Mutex<[[Slot; MAX_HANDLERS_PER_LINE]; IRQ_LINES as usize]> =
+    Mutex::new([[EMPTY_SLOT; MAX_HANDLERS_PER_LINE]; IRQ_LINES as usize]);
+
+/**
+ * call handler every time irq fires
+ * the line is unmasked when its first handler is registered
+ */
+pub fn register_irq(irq: u8, handler: IrqHandler) -> Result<IrqHandlerId, IrqError> {
+    if irq >= IRQ_LINES || (irq >= ISA_IRQ_LINES && !super::apic::has_gsi(irq as u32)) {
+        return Err(IrqError::InvalidIrq);
+    }
+    without_interrupts(|| {
+        let mut handlers = HANDLERS.lock();
+        let line = &mut handlers[irq as usize];
+        let first_handler = line.iter().all(|slot| slot.handler.is_none());
+        let slot = line.iter().position(|slot| slot.handler.is_none()).ok_or(IrqError::LineFull)?;
+        let generation = line[slot].generation.wrapping_add(1);
+        line[slot] = Slot { handler: Some(handler), generation };
+        if first_handler {
+            super::unmask_irq(irq);
+        }
+        Ok(IrqHandlerId { irq, slot, generation })
+    })
+}
+
+/**
+ * stop calling a registered handler, false if it was already unregistered
+ * the line is masked again once nothing is listening on it
+ */
+pub fn unregister_irq(id: IrqHandlerId) -> bool {
+    without_interrupts(|| {
+        let mut handlers = HANDLERS.lock();
+        let line = &mut handlers[id.irq as usize];
+        let slot = &mut line[id.slot];
+        if slot.generation != id.generation || slot.handler.take().is_none() {
+            return false;
+        }
+        if line.iter().all(|slot| slot.handler.is_none()) {
+            super::mask_irq(id.irq);
+        }
+        true
+    })
+}
+
+pub(super) fn set_handlers(idt: &mut InterruptDescriptorTable){
+    // generates one x86-interrupt stub per vector, all calling irq_handler with the vector number
+    set_general_handler!(idt, irq_handler, 32..56);
+}
+
+fn irq_handler(_stack_frame: InterruptStackFrame, vector: u8, _error_code: Option<u64>){
+    super::stats::record(vector);
+    let irq = vector - PIC_1_OFFSET;
+    // a copy, so handlers can register and unregister without deadlocking
+    let handlers = HANDLERS.lock()[irq as usize];
+    let listening = handlers.iter().any(|slot| slot.handler.is_some());
+    // spurious irqs don't get handlers or a normal end of interrupt
+    if super::handle_spurious_irq(irq, listening) {
+        return;
+    }
+    for handler in handlers.iter().filter_map(|slot| slot.handler) {
+        handler(irq);
+    }
+    super::end_of_interrupt(irq);
+}
+
+#[test_case]
+fn test_register_and_unregister(){
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+    fn count_call(_irq: u8){
+        CALLS.fetch_add(1, Ordering::SeqCst);
+    }
+    // shares line 0 with the timer handler
+    let id = register_irq(0, count_call).expect("registering on the timer line failed");
+    while CALLS.load(Ordering::SeqCst) == 0 {
+        x86_64::instructions::hlt();
+    }
+    assert!(unregister_irq(id));
+    assert!(!unregister_irq(id));
+    let calls = CALLS.load(Ordering::SeqCst);
+    x86_64::instructions::hlt();
+    assert_eq!(CALLS.load(Ordering::SeqCst), calls);
+}
+
+#[test_case]
+fn test_stale_id_keeps_the_slot_owner(){
+    fn first(_irq: u8){}
+    fn second(_irq: u8){}
+    // line 1 is the keyboard's, so these take a slot next to it
+    let old = register_irq(1, first).expect("registering on the keyboard line failed");
+    assert!(unregister_irq(old));
+    let new = register_irq(1, second).expect("registering on the keyboard line failed");
+    assert_eq!((new.irq, new.slot), (old.irq, old.slot));
+    assert!(!unregister_irq(old));
+    assert!(unregister_irq(new));
+}
+
+#[test_case]
+fn test_invalid_irq_is_rejected(){
+    fn nothing(_irq: u8){}
+    assert_eq!(register_irq(IRQ_LINES, nothing), Err(IrqError::InvalidIrq));
+}
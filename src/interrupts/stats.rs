@@ -0,0 +1,144 @@
+// how many times each interrupt vector has fired since boot
+// every handler bumps its vector's counter, ex: for tests to check the timer is running
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use super::PIC_1_OFFSET;
+use super::irq::IRQ_LINES;
+
+const VECTORS: usize = 256;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+static COUNTS: [AtomicU64; VECTORS] = [ZERO; VECTORS];
+// spurious PIC and local APIC interrupts, counted on top of their vector
+static SPURIOUS: AtomicU64 = AtomicU64::new(0);
+
+// cheap enough for every interrupt - one atomic add, no locks
+pub(super) fn record(vector: u8){
+    COUNTS[vector as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+pub(super) fn record_spurious(){
+    SPURIOUS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn count(vector: u8) -> u64 {
+    COUNTS[vector as usize].load(Ordering::Relaxed)
+}
+
+pub fn spurious_count() -> u64 {
+    SPURIOUS.load(Ordering::Relaxed)
+}
+
+/**
+ * all counters copied at one point in time
+ * not atomic as a whole - an interrupt can land between two counters being read
+ */
+#[derive(Clone)]
+pub struct InterruptStats{
+    counts: [u64; VECTORS],
+    spurious: u64,
+}
+
+pub fn snapshot() -> InterruptStats {
+    let mut counts = [0; VECTORS];
+    for (count, counter) in counts.iter_mut().zip(COUNTS.iter()) {
+        *count = counter.load(Ordering::Relaxed);
+    }
+    InterruptStats { counts, spurious: spurious_count() }
+}
+
+// print every vector that has fired to serial
+pub fn dump(){
+    crate::serial_println!("{}", snapshot());
+}
+
+impl InterruptStats{
+    pub fn count(&self, vector: u8) -> u64 {
+        self.counts[vector as usize]
+    }
+
+    // count for a hardware irq line, ex: irq(0) for the timer
+    pub fn irq(&self, irq: u8) -> u64 {
+        assert!(irq < IRQ_LINES, "no irq line {}", irq);
+        self.count(PIC_1_OFFSET + irq)
+    }
+
+    pub fn spurious(&self) -> u64 {
+        self.spurious
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    // what happened between earlier and this snapshot
+    pub fn since(&self, earlier: &InterruptStats) -> InterruptStats {
+        let mut counts = [0; VECTORS];
+        for (vector, count) in counts.iter_mut().enumerate() {
+            *count = self.counts[vector].saturating_sub(earlier.counts[vector]);
+        }
+        InterruptStats { counts, spurious: self.spurious.saturating_sub(earlier.spurious) }
+    }
+}
+
+// cpu exceptions by vector number, reserved vectors are None
+const EXCEPTION_NAMES: [Option<&str>; 32] = [
+    Some("divide error"), Some("debug"), Some("non maskable interrupt"), Some("breakpoint"),
+    Some("overflow"), Some("bound range exceeded"), Some("invalid opcode"), Some("device not available"),
+    Some("double fault"), None, Some("invalid tss"), Some("segment not present"),
+    Some("stack segment fault"), Some("general protection fault"), Some("page fault"), None,
+    Some("x87 floating point"), Some("alignment check"), Some("machine check"), Some("simd floating point"),
+    Some("virtualization"), None, None, None,
+    None, None, None, None,
+    None, None, Some("security exception"), None,
+];
+
+fn describe(vector: u8, f: &mut fmt::Formatter) -> fmt::Result {
+    match vector {
+        0..=31 => write!(f, "{}", EXCEPTION_NAMES[vector as usize].unwrap_or("reserved")),
+        v if (PIC_1_OFFSET..PIC_1_OFFSET + IRQ_LINES).contains(&v) => {
+            let irq = v - PIC_1_OFFSET;
+            match irq {
+                0 => write!(f, "irq 0 (timer)"),
+                1 => write!(f, "irq 1 (keyboard)"),
+                12 => write!(f, "irq 12 (mouse)"),
+                _ => write!(f, "irq {}", irq),
+            }
+        }
+        super::apic::SPURIOUS_VECTOR => write!(f, "apic spurious"),
+        _ => write!(f, "unassigned"),
+    }
+}
+
+// one line per vector that has fired, ex: "  32 irq 0 (timer): 1234"
+impl fmt::Display for InterruptStats{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "interrupts: {} total, {} spurious", self.total(), self.spurious)?;
+        for (vector, &count) in self.counts.iter().enumerate() {
+            if count == 0 {
+                continue;
+            }
+            write!(f, "  {:>3} ", vector)?;
+            describe(vector as u8, f)?;
+            writeln!(f, ": {}", count)?;
+        }
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_timer_is_counted(){
+    let before = snapshot();
+    for _ in 0..3 {
+        x86_64::instructions::hlt();
+    }
+    assert!(snapshot().since(&before).irq(0) >= 3);
+}
+
+#[test_case]
+fn test_breakpoint_is_counted(){
+    let before = count(3);
+    x86_64::instructions::interrupts::int3();
+    assert_eq!(count(3), before + 1);
+}
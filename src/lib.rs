@@ -8,21 +8,115 @@
 #![reexport_test_harness_main = "test_main"]
 //allows unstable abi to be used
 #![feature(abi_x86_interrupt)]
+// lets us decide what happens when the heap runs out
+#![feature(alloc_error_handler)]
+// Box, Vec, String, etc - usable once allocator::init_heap has run
+extern crate alloc;
 use core::panic::PanicInfo;
 pub mod serial;
 pub mod vga_buffer;
 pub mod interrupts;
 // global descriptor table
 pub mod gdt;
+// physical frames and paging
+pub mod memory;
+// kernel heap
+pub mod allocator;
+// register dump and backtrace when the kernel goes down
+pub mod crash;
+// function names for backtraces
+pub mod symbols;
+// firmware tables describing the hardware
+pub mod acpi;
+// timer ticks and uptime
+pub mod time;
+// async tasks and the executor that runs them
+pub mod task;
+// the 8042 keyboard and mouse controller
+pub mod ps2;
+// options from the qemu command line
+pub mod fw_cfg;
+// shared by the tests/exception_*.rs kernels, ex: cargo test --features exception-tests
+#[cfg(any(test, feature = "exception-tests"))]
+pub mod exception_test;
 
+/**
+ * memory has to be initialized first - see memory::init_from_boot_info
+ * the APIC when there is one, otherwise the PICs - a machine without a MADT gets the PICs
+ * the interrupts boot option picks one at boot, ex: to force the PICs with qemu
+ * -fw_cfg name=opt/learning_os/interrupts,string=pic
+ */
 pub fn init(){
+    init_with(boot_interrupt_controller());
+}
+
+// from the interrupts boot option, the APIC if it's missing or isn't apic or pic
+fn boot_interrupt_controller() -> interrupts::InterruptController {
+    let mut buf = [0; 16];
+    match fw_cfg::boot_option("interrupts", &mut buf) {
+        Some(name) => name.parse().unwrap_or_else(|()| {
+            serial_println!("unknown interrupts boot option {:?}, using the APIC", name);
+            interrupts::InterruptController::Apic
+        }),
+        None => interrupts::InterruptController::Apic,
+    }
+}
+
+/**
+ * from the keyboard_layout and scancode_set boot options, ex:
+ * -fw_cfg name=opt/learning_os/keyboard_layout,string=de
+ * anything missing or unknown keeps the default - see task::keyboard::KeyboardConfig
+ */
+fn boot_keyboard_config() -> task::keyboard::KeyboardConfig {
+    let mut config = task::keyboard::KeyboardConfig::default();
+    let mut buf = [0; 16];
+    if let Some(name) = fw_cfg::boot_option("keyboard_layout", &mut buf) {
+        match name.parse() {
+            Ok(layout) => config.layout = layout,
+            Err(()) => {
+                serial_println!("unknown keyboard_layout boot option {:?}, using {:?}", name, config.layout);
+            }
+        }
+    }
+    if let Some(name) = fw_cfg::boot_option("scancode_set", &mut buf) {
+        match name.parse() {
+            Ok(scancode_set) => config.scancode_set = scancode_set,
+            Err(()) => {
+                serial_println!("unknown scancode_set boot option {:?}, using {:?}", name, config.scancode_set);
+            }
+        }
+    }
+    config
+}
+
+// init, but picking the interrupt controller - ex: InterruptController::Pic to skip the APIC
+pub fn init_with(controller: interrupts::InterruptController){
     // init the gdt -> to use TSS -> to use IST for stackoverflow err
     gdt::init();
     // init interrupts
     interrupts::init_idt();
     // unsafe since undefined behavior can happen
-    unsafe {interrupts::PICS.lock().initialize()};
-    // make it so that the CPU listens to pic interrupts
+    unsafe {interrupts::init_controller(controller)};
+    // start the timer ticking
+    time::init();
+    // before ps2::init, which sets the controller's translation to match the scancode set
+    task::keyboard::set_boot_config(boot_keyboard_config());
+    // keyboard controller and mouse, with interrupts still off so their answers can be polled
+    match ps2::init() {
+        Ok(controller) if controller.second_port => {
+            if let Err(err) = task::mouse::init() {
+                serial_println!("no PS/2 mouse ({:?})", err);
+            }
+        }
+        Ok(_) => {}
+        Err(err) => {
+            serial_println!("no PS/2 controller ({:?})", err);
+            // nothing turned translation off, whatever bytes come in are set 1
+            let config = task::keyboard::config();
+            task::keyboard::set_boot_config(task::keyboard::KeyboardConfig { scancode_set: task::keyboard::ScancodeSet::Set1, ..config });
+        }
+    }
+    // make it so that the CPU listens to hardware interrupts
     x86_64::instructions::interrupts::enable(); 
 }
 
@@ -53,13 +147,21 @@ pub fn test_runner(tests: &[&dyn Testable]){
 
 pub fn test_panic_handler(info: &PanicInfo) -> !{
     serial_println!("[failed]\n");
-    serial_println!("[Error info: {}]\n", info);
+    serial_println!("[Error info: {}] at {}\n", info, time::Timestamp);
+    crash::report_panic(info);
     exit_qemu(QemuExitCode::Failed);
     hlt_loop();
 }
 
 
 
+// called when the global allocator can't satisfy a request
+#[alloc_error_handler]
+fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
+    serial_println!("[allocation error: {:?}]", layout);
+    panic!("allocation error: {:?}", layout)
+}
+
 pub fn hlt_loop() -> ! {
     // sleep until the next instruction arrives
     loop{
@@ -67,12 +169,19 @@ pub fn hlt_loop() -> ! {
     }
 }
 
+#[cfg(test)]
+use bootloader::{entry_point, BootInfo};
+
 //lib is it's own separately compiled attribute
 // as such it needs it's own entry point
 #[cfg(test)]
-#[no_mangle]
-pub extern "C" fn _start() -> !{
-    init(); 
+entry_point!(test_kernel_main);
+
+#[cfg(test)]
+fn test_kernel_main(boot_info: &'static BootInfo) -> !{
+    unsafe { memory::init_from_boot_info(boot_info) };
+    init();
+    allocator::init_heap().expect("heap initialization failed");
     test_main();
     hlt_loop();
 }
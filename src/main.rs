@@ -8,34 +8,66 @@
 #![reexport_test_harness_main = "test_main"]
 use core::panic::PanicInfo;
 use learning_os::println;
+use bootloader::{BootInfo, entry_point};
+use learning_os::{allocator, memory};
+use learning_os::task::{keyboard, mouse, Executor, Task};
+extern crate alloc;
+use alloc::{boxed::Box, vec::Vec};
 
 
 // To build for QEMU -  cargo bootimage; 
 // To run with QEMU - qemu-system-x86_64 -drive format=raw,file=target/x86_64-buildData/debug/bootimage-learning_os.bin
 // To test - cargo test
+// cargo run and cargo test embed the symbol table for backtraces, a plain cargo bootimage doesn't
 
-/**
- * new entry point - no runtime is calling main anymore
- * 
- * disable name mangling - needed to tell
- * the entry point fn name to the linker
- * extern C - uses C calling convention
- */
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
+// new entry point - no runtime is calling main anymore
+// entry_point! defines the real _start for us and type checks
+// that kernel_main takes the BootInfo the bootloader passes in
+entry_point!(kernel_main);
+
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
     println!("Hello Universe{}", "!");
+    // hand the bootloader's view of physical memory to the page tables and frame allocator
+    // before anything else, the gdt's interrupt stacks are mapped through them
+    unsafe { memory::init_from_boot_info(boot_info) };
     //initialize the idt, set the breakpoint handler
     learning_os::init();
 
-    // triggering a page fault to understand paging errors
-    let ptr = 0x205280 as *mut u8;
-    unsafe {let x = *ptr;}
-    println!("Successful reading");
+    // leave the bootloader's stack for one with a guard page we know about
+    let kernel_stack = memory::stack::allocate("kernel stack", KERNEL_STACK_PAGES)
+        .expect("kernel stack allocation failed");
+    unsafe { memory::stack::switch_to(&kernel_stack, kernel_main_on_kernel_stack) }
+}
+
+// 80 KiB
+const KERNEL_STACK_PAGES: u64 = 20;
 
-    // will throw an error since we can't write to a code page
-    // Hence the Protection_Violation Error Code
-    unsafe {*ptr = 42;}
-    learning_os::hlt_loop();
+extern "C" fn kernel_main_on_kernel_stack() -> ! {
+    use x86_64::VirtAddr;
+    // the vga buffer is identity mapped, so it translates to itself
+    let vga_buffer = VirtAddr::new(0xb8000);
+    println!("{:?} -> {:?}", vga_buffer, memory::translate_addr(vga_buffer));
+
+    // heap needs the page tables and frame allocator to back it
+    allocator::init_heap().expect("heap initialization failed");
+    let heap_value = Box::new(41);
+    println!("heap_value at {:p}", heap_value);
+    let mut vec = Vec::new();
+    for i in 0..500 {
+        vec.push(i);
+    }
+    println!("vec at {:p}", vec.as_slice());
+
+    // everything from here on runs as async tasks, the executor halts when they're all waiting
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(example_task()));
+    executor.spawn(Task::new(countdown_task()));
+    executor.spawn(Task::new(keyboard::print_keypresses()));
+    executor.spawn(Task::new(keyboard::update_leds()));
+    if mouse::is_initialized() {
+        executor.spawn(Task::new(mouse::print_mouse_events()));
+    }
+    executor.run();
     //invoke a breakpoint exception to test the handler
     // x86_64::instructions::interrupts::int3();
 
@@ -54,13 +86,32 @@ pub extern "C" fn _start() -> ! {
     // println!("Successfully caught a breakpoint and didn't crash.");
 }
 
+async fn async_number() -> u32 {
+    42
+}
+
+async fn example_task(){
+    let number = async_number().await;
+    println!("async number: {}", number);
+}
+
+// hands the cpu to the other tasks in between steps
+async fn countdown_task(){
+    for i in (1..=3).rev() {
+        println!("countdown: {}", i);
+        learning_os::task::yield_now().await;
+    }
+}
+
 
 // non-test panic handler
 #[cfg(not(test))]
 #[panic_handler] 
 fn panic(_info: &PanicInfo) -> ! {
     // post output in qemu 
-    println!("{}", _info);
+    println!("[{}] {}", learning_os::time::Timestamp, _info);
+    // registers and backtrace, unless the exception that caused this already printed them
+    learning_os::crash::report_panic(_info);
     loop {}
 }
 
@@ -0,0 +1,373 @@
+// Gregory Vincent
+// physical memory management and paging
+// hands out the 4KiB frames the bootloader marked as usable in its memory map
+// and maps them into the virtual address space through the active page tables
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use bootloader::BootInfo;
+use spin::Mutex;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+    PhysFrame, Size4KiB, Translate,
+};
+use x86_64::structures::paging::mapper::{FlagUpdateError, MapToError, UnmapError};
+use x86_64::{PhysAddr, VirtAddr};
+
+// regions that get frames mapped on first access
+pub mod lazy;
+// kernel and interrupt stacks with guard pages
+pub mod stack;
+// uncached mappings for device registers
+pub mod mmio;
+
+pub const FRAME_SIZE: u64 = 4096;
+
+/**
+ * The active page tables, reached through the bootloader's physical memory mapping
+ * None until init is called
+ * Lock order: the heap allocator, then MAPPER, then FRAME_ALLOCATOR
+ * the heap grows through map_page, so nothing may allocate while holding either
+ */
+pub static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+
+/**
+ * # Safety
+ * the caller has to guarantee that all of physical memory
+ * is mapped at physical_memory_offset, and this should only be called once
+ * so there's never two &mut references to the level 4 table
+ */
+pub unsafe fn init(physical_memory_offset: VirtAddr){
+    let level_4_table = active_level_4_table(physical_memory_offset);
+    *MAPPER.lock() = Some(OffsetPageTable::new(level_4_table, physical_memory_offset));
+}
+
+/**
+ * init plus init_frame_allocator from what the bootloader passed to the entry point
+ * has to run before gdt::init since the interrupt stacks are mapped through the page tables
+ * # Safety
+ * only call once, with the BootInfo the bootloader handed over
+ */
+pub unsafe fn init_from_boot_info(boot_info: &'static BootInfo){
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    init(physical_memory_offset);
+    init_frame_allocator(&boot_info.memory_map, physical_memory_offset);
+}
+
+// the CR3 register holds the physical frame of the level 4 table in use
+unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
+    use x86_64::registers::control::Cr3;
+    let (level_4_table_frame, _) = Cr3::read();
+    let physical_address = level_4_table_frame.start_address();
+    let virtual_address = physical_memory_offset + physical_address.as_u64();
+    let page_table_ptr: *mut PageTable = virtual_address.as_mut_ptr();
+    &mut *page_table_ptr
+}
+
+// runs f with both the page tables and the frame allocator locked
+fn with_mapper<R>(f: impl FnOnce(&mut OffsetPageTable<'static>, &mut BootInfoFrameAllocator) -> R) -> R {
+    let mut mapper = MAPPER.lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    f(
+        mapper.as_mut().expect("page tables not initialized"),
+        frame_allocator.as_mut().expect("frame allocator not initialized"),
+    )
+}
+
+/**
+ * back page with a freshly allocated, zeroed frame
+ * returns the frame so the caller knows where the page lives physically
+ */
+pub fn map_page(page: Page, flags: PageTableFlags) -> Result<PhysFrame, MapToError<Size4KiB>> {
+    with_mapper(|mapper, frame_allocator| map_zeroed(mapper, frame_allocator, page, flags))
+}
+
+/**
+ * map_page for code that might run while MAPPER or FRAME_ALLOCATOR is already locked, ex: a page fault
+ * None if either is busy or not set up yet
+ */
+pub fn try_map_page(page: Page, flags: PageTableFlags) -> Option<Result<PhysFrame, MapToError<Size4KiB>>> {
+    let mut mapper = MAPPER.try_lock()?;
+    let mut frame_allocator = FRAME_ALLOCATOR.try_lock()?;
+    Some(map_zeroed(mapper.as_mut()?, frame_allocator.as_mut()?, page, flags))
+}
+
+fn map_zeroed(
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut BootInfoFrameAllocator,
+    page: Page,
+    flags: PageTableFlags,
+) -> Result<PhysFrame, MapToError<Size4KiB>> {
+    let frame = frame_allocator.allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
+    // zero through the physical memory mapping - the page itself might not be writable
+    let frame_ptr: *mut u8 = (mapper.phys_offset() + frame.start_address().as_u64()).as_mut_ptr();
+    unsafe { frame_ptr.write_bytes(0, FRAME_SIZE as usize) };
+    // unsafe - the frame is fresh, so nothing else can be aliasing it
+    match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+        Ok(flush) => {
+            flush.flush();
+            Ok(frame)
+        }
+        Err(err) => {
+            unsafe { frame_allocator.deallocate_frame(frame) };
+            Err(err)
+        }
+    }
+}
+
+/**
+ * map page to a specific frame, ex: memory mapped hardware
+ * # Safety
+ * the caller has to guarantee mapping the frame doesn't alias memory already in use
+ */
+pub unsafe fn map_page_to_frame(page: Page, frame: PhysFrame, flags: PageTableFlags) -> Result<(), MapToError<Size4KiB>> {
+    with_mapper(|mapper, frame_allocator| {
+        mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+        Ok(())
+    })
+}
+
+/**
+ * remove page from the page tables, returning the frame it pointed to
+ * the frame isn't freed - use free_page for pages that came from map_page
+ * # Safety
+ * nothing may still be using memory in the page
+ */
+pub unsafe fn unmap_page(page: Page) -> Result<PhysFrame, UnmapError> {
+    with_mapper(|mapper, _| {
+        let (frame, flush) = mapper.unmap(page)?;
+        flush.flush();
+        Ok(frame)
+    })
+}
+
+/**
+ * unmap a page that came from map_page and give its frame back to the frame allocator
+ * # Safety
+ * nothing may still be using memory in the page
+ */
+pub unsafe fn free_page(page: Page) -> Result<(), UnmapError> {
+    with_mapper(|mapper, frame_allocator| {
+        let (frame, flush) = mapper.unmap(page)?;
+        flush.flush();
+        frame_allocator.deallocate_frame(frame);
+        Ok(())
+    })
+}
+
+/**
+ * replace the flags of an already mapped page, ex: making it read only
+ * # Safety
+ * taking away access from memory that's still in use will fault
+ */
+pub unsafe fn remap_page(page: Page, flags: PageTableFlags) -> Result<(), FlagUpdateError> {
+    with_mapper(|mapper, _| {
+        mapper.update_flags(page, flags)?.flush();
+        Ok(())
+    })
+}
+
+// physical address a virtual address points to, None if it isn't mapped
+pub fn translate_addr(addr: VirtAddr) -> Option<PhysAddr> {
+    MAPPER.lock().as_ref().expect("page tables not initialized").translate_addr(addr)
+}
+
+/**
+ * where the bootloader's mapping of all physical memory makes addr readable
+ * for RAM, ex: ACPI tables - device registers go through mmio::map instead
+ */
+pub fn physical_to_virtual(addr: PhysAddr) -> VirtAddr {
+    MAPPER.lock().as_ref().expect("page tables not initialized").phys_offset() + addr.as_u64()
+}
+
+/**
+ * translate_addr for code that might run while MAPPER is already locked, ex: a crash report
+ * None if the address isn't mapped, or the page tables are busy or not set up yet
+ */
+pub fn try_translate_addr(addr: VirtAddr) -> Option<PhysAddr> {
+    MAPPER.try_lock()?.as_ref()?.translate_addr(addr)
+}
+
+/**
+ * Only one frame allocator can exist - two of them would hand out the same frames
+ * None until init_frame_allocator is called with the bootloader's memory map
+ */
+pub static FRAME_ALLOCATOR: Mutex<Option<BootInfoFrameAllocator>> = Mutex::new(None);
+
+/**
+ * # Safety
+ * the caller has to guarantee the memory map is valid
+ * and that all of physical memory is mapped at physical_memory_offset
+ */
+pub unsafe fn init_frame_allocator(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr){
+    *FRAME_ALLOCATOR.lock() = Some(BootInfoFrameAllocator::init(memory_map, physical_memory_offset));
+}
+
+/**
+ * Frames come from two places:
+ * 1. the free list - frames that were handed out and then given back
+ * 2. the memory map - walked region by region, frame by frame, never revisited
+ */
+pub struct BootInfoFrameAllocator{
+    memory_map: &'static MemoryMap,
+    // region of the memory map we're currently taking fresh frames from
+    region_index: usize,
+    // start address of the next fresh frame inside that region
+    next_frame: u64,
+    /**
+     * freed frames form a linked list through themselves
+     * the first 8 bytes of each free frame hold the address of the next one
+     * 0 ends the list - frame zero is never usable so it can't be confused for a real frame
+     */
+    free_list_head: Option<PhysFrame>,
+    // needed to write the free list links into physical frames
+    physical_memory_offset: VirtAddr,
+}
+
+impl BootInfoFrameAllocator{
+    /**
+     * # Safety
+     * the caller has to guarantee every Usable region in the map really is unused
+     * and that all of physical memory is mapped at physical_memory_offset
+     */
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        BootInfoFrameAllocator{
+            memory_map,
+            region_index: 0,
+            next_frame: 0,
+            free_list_head: None,
+            physical_memory_offset,
+        }
+    }
+
+    // virtual address of the free list link stored at the start of a frame
+    fn link(&self, frame: PhysFrame) -> *mut u64 {
+        (self.physical_memory_offset + frame.start_address().as_u64()).as_mut_ptr()
+    }
+
+    fn pop_free_frame(&mut self) -> Option<PhysFrame> {
+        let frame = self.free_list_head?;
+        let next = unsafe { self.link(frame).read_volatile() };
+        self.free_list_head = match next {
+            0 => None,
+            addr => Some(PhysFrame::containing_address(PhysAddr::new(addr))),
+        };
+        Some(frame)
+    }
+
+    fn next_fresh_frame(&mut self) -> Option<PhysFrame> {
+        while let Some(region) = self.memory_map.get(self.region_index) {
+            if region.region_type == MemoryRegionType::Usable {
+                // regions aren't guaranteed to start on a frame boundary
+                let region_start = x86_64::align_up(region.range.start_addr(), FRAME_SIZE);
+                if self.next_frame < region_start {
+                    self.next_frame = region_start;
+                }
+                if self.next_frame + FRAME_SIZE <= region.range.end_addr() {
+                    let frame = PhysFrame::containing_address(PhysAddr::new(self.next_frame));
+                    self.next_frame += FRAME_SIZE;
+                    return Some(frame);
+                }
+            }
+            // region is used up or was never usable, move on to the next one
+            self.region_index += 1;
+            self.next_frame = 0;
+        }
+        None
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator{
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        // reuse returned frames before eating into the memory map
+        self.pop_free_frame().or_else(|| self.next_fresh_frame())
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator{
+    /**
+     * # Safety
+     * the frame must have come from this allocator
+     * and must not be mapped or used anywhere anymore
+     */
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame){
+        let next = self.free_list_head.map_or(0, |head| head.start_address().as_u64());
+        self.link(frame).write_volatile(next);
+        self.free_list_head = Some(frame);
+    }
+}
+
+#[test_case]
+fn test_freed_frame_is_reused(){
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let allocator = allocator.as_mut().expect("frame allocator not initialized");
+    let frame = allocator.allocate_frame().expect("out of physical frames");
+    unsafe { allocator.deallocate_frame(frame) };
+    assert_eq!(allocator.allocate_frame(), Some(frame));
+}
+
+#[test_case]
+fn test_frames_are_distinct(){
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let allocator = allocator.as_mut().expect("frame allocator not initialized");
+    let first = allocator.allocate_frame().expect("out of physical frames");
+    let second = allocator.allocate_frame().expect("out of physical frames");
+    assert_ne!(first, second);
+    // the free list is last in first out
+    unsafe {
+        allocator.deallocate_frame(first);
+        allocator.deallocate_frame(second);
+    }
+    assert_eq!(allocator.allocate_frame(), Some(second));
+    assert_eq!(allocator.allocate_frame(), Some(first));
+}
+
+// far away from anything the bootloader or kernel maps
+#[cfg(test)]
+const TEST_PAGE_ADDR: u64 = 0x_3333_3333_0000;
+
+#[test_case]
+fn test_translate_identity_mapped_vga_buffer(){
+    // the bootloader identity maps the first megabyte, including the vga buffer
+    assert_eq!(translate_addr(VirtAddr::new(0xb8000)), Some(PhysAddr::new(0xb8000)));
+}
+
+#[test_case]
+fn test_map_write_and_translate(){
+    let page = Page::containing_address(VirtAddr::new(TEST_PAGE_ADDR));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let frame = map_page(page, flags).expect("map_page failed");
+    // offsets inside the page carry over to the frame
+    let addr = page.start_address() + 0x123u64;
+    assert_eq!(translate_addr(addr), Some(frame.start_address() + 0x123u64));
+    let ptr: *mut u64 = page.start_address().as_mut_ptr();
+    unsafe {
+        // fresh pages start zeroed
+        assert_eq!(ptr.read_volatile(), 0);
+        ptr.write_volatile(0x_f021_f077_f065_f04e);
+        assert_eq!(ptr.read_volatile(), 0x_f021_f077_f065_f04e);
+        free_page(page).expect("free_page failed");
+    }
+    assert_eq!(translate_addr(addr), None);
+}
+
+#[test_case]
+fn test_map_twice_fails(){
+    let page = Page::containing_address(VirtAddr::new(TEST_PAGE_ADDR));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    map_page(page, flags).expect("map_page failed");
+    assert!(matches!(map_page(page, flags), Err(MapToError::PageAlreadyMapped(_))));
+    unsafe { free_page(page).expect("free_page failed") };
+}
+
+#[test_case]
+fn test_remap_changes_flags(){
+    use x86_64::structures::paging::mapper::TranslateResult;
+    let page = Page::containing_address(VirtAddr::new(TEST_PAGE_ADDR));
+    map_page(page, PageTableFlags::PRESENT | PageTableFlags::WRITABLE).expect("map_page failed");
+    unsafe { remap_page(page, PageTableFlags::PRESENT).expect("remap_page failed") };
+    let translation = MAPPER.lock().as_ref().unwrap().translate(page.start_address());
+    match translation {
+        TranslateResult::Mapped { flags, .. } => assert!(!flags.contains(PageTableFlags::WRITABLE)),
+        _ => panic!("page should still be mapped"),
+    }
+    unsafe { free_page(page).expect("free_page failed") };
+}
@@ -0,0 +1,154 @@
+// demand paging - regions of virtual memory that only get physical frames once they're touched
+// the first access to a page faults, the page fault handler maps a zeroed frame and the access retries
+use spin::Mutex;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::{mapper::UnmapError, Page, PageTableFlags};
+use x86_64::VirtAddr;
+use super::FRAME_SIZE;
+
+// fixed size so the page fault handler never has to touch the heap
+const MAX_LAZY_REGIONS: usize = 32;
+
+#[derive(Debug, Clone, Copy)]
+struct LazyRegion {
+    start: VirtAddr,
+    // exclusive
+    end: VirtAddr,
+    flags: PageTableFlags,
+}
+
+impl LazyRegion {
+    fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    fn overlaps(&self, other: &LazyRegion) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+
+    fn pages(&self) -> impl Iterator<Item = Page> {
+        Page::range(Page::containing_address(self.start), Page::containing_address(self.end))
+    }
+}
+
+static LAZY_REGIONS: Mutex<[Option<LazyRegion>; MAX_LAZY_REGIONS]> = Mutex::new([None; MAX_LAZY_REGIONS]);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LazyRegionError {
+    // start or size isn't a multiple of the page size, or size is 0
+    Unaligned,
+    // part of the range is already a lazy region
+    Overlap,
+    // all MAX_LAZY_REGIONS slots are taken
+    Full,
+}
+
+/**
+ * reserve size bytes at start - nothing gets mapped until a page in it is touched
+ * pages are mapped with flags once they fault in, PRESENT is added automatically
+ * the caller is responsible for picking a range nothing else maps
+ */
+pub fn reserve(start: VirtAddr, size: u64, flags: PageTableFlags) -> Result<(), LazyRegionError> {
+    if size == 0 || !start.is_aligned(FRAME_SIZE) || !size.is_multiple_of(FRAME_SIZE) {
+        return Err(LazyRegionError::Unaligned);
+    }
+    let region = LazyRegion {
+        start,
+        end: start + size,
+        flags: flags | PageTableFlags::PRESENT,
+    };
+    let mut regions = LAZY_REGIONS.lock();
+    if regions.iter().flatten().any(|existing| existing.overlaps(&region)) {
+        return Err(LazyRegionError::Overlap);
+    }
+    let slot = regions.iter_mut().find(|slot| slot.is_none()).ok_or(LazyRegionError::Full)?;
+    *slot = Some(region);
+    Ok(())
+}
+
+/**
+ * forget the lazy region starting at start, unmapping and freeing every page that faulted in
+ * false if there's no region starting there
+ * # Safety
+ * nothing may still be using memory in the region
+ */
+pub unsafe fn release(start: VirtAddr) -> bool {
+    let region = {
+        let mut regions = LAZY_REGIONS.lock();
+        match regions.iter_mut().find(|slot| matches!(slot, Some(region) if region.start == start)) {
+            Some(slot) => slot.take().unwrap(),
+            None => return false,
+        }
+    };
+    for page in region.pages() {
+        match super::free_page(page) {
+            // never touched, so never mapped
+            Ok(()) | Err(UnmapError::PageNotMapped) => {}
+            Err(err) => panic!("failed to release lazy page {:?}: {:?}", page, err),
+        }
+    }
+    true
+}
+
+/**
+ * called by the page fault handler
+ * true if addr is in a lazy region and its page is now mapped, so the access can be retried
+ * false means the fault is a real error
+ * the fault can hit while the regions or the page tables are locked, those get reported
+ * as a fatal fault instead of spinning forever on the lock
+ */
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    // the page is there but the access isn't allowed - mapping it again won't help
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return false;
+    }
+    let region = match LAZY_REGIONS.try_lock() {
+        Some(regions) => match regions.iter().flatten().find(|region| region.contains(addr)) {
+            Some(region) => *region,
+            None => return false,
+        },
+        None => return false,
+    };
+    // hands back a zeroed frame
+    matches!(super::try_map_page(Page::containing_address(addr), region.flags), Some(Ok(_)))
+}
+
+// far away from the heap and the mapping tests
+#[cfg(test)]
+const TEST_REGION_ADDR: u64 = 0x_5555_5555_0000;
+
+#[test_case]
+fn test_lazy_region_faults_in_on_access(){
+    let start = VirtAddr::new(TEST_REGION_ADDR);
+    let flags = PageTableFlags::WRITABLE;
+    reserve(start, 4 * FRAME_SIZE, flags).expect("reserve failed");
+    // nothing is mapped until it's touched
+    assert_eq!(super::translate_addr(start), None);
+
+    let second_page: *mut u64 = (start + FRAME_SIZE).as_mut_ptr();
+    unsafe {
+        // readable, and zeroed
+        assert_eq!(second_page.read_volatile(), 0);
+        // writable
+        second_page.write_volatile(42);
+        assert_eq!(second_page.read_volatile(), 42);
+    }
+    // only the touched page got a frame
+    assert!(super::translate_addr(start + FRAME_SIZE).is_some());
+    assert_eq!(super::translate_addr(start), None);
+
+    unsafe { assert!(release(start)) };
+    assert_eq!(super::translate_addr(start + FRAME_SIZE), None);
+}
+
+#[test_case]
+fn test_overlapping_reserve_fails(){
+    let start = VirtAddr::new(TEST_REGION_ADDR);
+    reserve(start, 2 * FRAME_SIZE, PageTableFlags::WRITABLE).expect("reserve failed");
+    assert_eq!(
+        reserve(start + FRAME_SIZE, 2 * FRAME_SIZE, PageTableFlags::WRITABLE),
+        Err(LazyRegionError::Overlap)
+    );
+    assert_eq!(reserve(start + 100u64, FRAME_SIZE, PageTableFlags::WRITABLE), Err(LazyRegionError::Unaligned));
+    unsafe { assert!(release(start)) };
+}
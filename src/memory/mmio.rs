@@ -0,0 +1,33 @@
+// memory mapped hardware registers, ex: the APICs
+// device memory must never be cached, so it gets its own uncached mappings
+// instead of going through the bootloader's physical memory mapping
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::structures::paging::{mapper::MapToError, Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+use super::FRAME_SIZE;
+
+// mappings are handed out upwards from here and never given back
+const MMIO_START: u64 = 0x_5000_0000_0000;
+static NEXT_MMIO: AtomicU64 = AtomicU64::new(MMIO_START);
+
+/**
+ * map size bytes of device memory starting at phys, returning where phys ended up
+ * phys doesn't have to be page aligned, the offset into its page is kept
+ * # Safety
+ * the caller has to make sure the range really is device memory, not RAM something else owns
+ */
+pub unsafe fn map(phys: PhysAddr, size: u64) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    let first_frame = PhysFrame::<Size4KiB>::containing_address(phys);
+    let last_frame = PhysFrame::<Size4KiB>::containing_address(phys + size.max(1) - 1u64);
+    let frames = PhysFrame::range_inclusive(first_frame, last_frame);
+    let pages = frames.end.start_address().as_u64() - frames.start.start_address().as_u64() + FRAME_SIZE;
+    let start = NEXT_MMIO.fetch_add(pages, Ordering::SeqCst);
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH;
+    for (i, frame) in frames.enumerate() {
+        let page = Page::containing_address(VirtAddr::new(start + i as u64 * FRAME_SIZE));
+        super::map_page_to_frame(page, frame, flags)?;
+    }
+    Ok(VirtAddr::new(start + (phys.as_u64() - first_frame.start_address().as_u64())))
+}
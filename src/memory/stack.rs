@@ -0,0 +1,125 @@
+// stacks backed by the paging subsystem, each with an unmapped guard page below it
+// running off the bottom of a stack hits the guard page and faults,
+// instead of silently writing over whatever memory is below it
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::structures::paging::{mapper::MapToError, Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+use super::FRAME_SIZE;
+
+// stacks are handed out upwards from here and never given back
+const STACKS_START: u64 = 0x_6666_0000_0000;
+static NEXT_STACK: AtomicU64 = AtomicU64::new(STACKS_START);
+
+// fixed size so the fault handlers never have to touch the heap
+const MAX_STACKS: usize = 16;
+static STACKS: Mutex<[Option<GuardedStack>; MAX_STACKS]> = Mutex::new([None; MAX_STACKS]);
+
+#[derive(Debug, Clone, Copy)]
+pub struct GuardedStack {
+    name: &'static str,
+    guard_page: Page,
+    // lowest mapped address
+    bottom: VirtAddr,
+    // one past the highest mapped address - stacks grow down, so rsp starts here
+    top: VirtAddr,
+}
+
+impl GuardedStack {
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn bottom(&self) -> VirtAddr {
+        self.bottom
+    }
+
+    pub fn top(&self) -> VirtAddr {
+        self.top
+    }
+}
+
+#[derive(Debug)]
+pub enum StackAllocationError {
+    // all MAX_STACKS slots are taken
+    TooManyStacks,
+    MapFailed(MapToError<Size4KiB>),
+}
+
+/**
+ * map a stack of pages pages with an unmapped guard page right below it
+ * name shows up in the report when the stack overflows
+ */
+pub fn allocate(name: &'static str, pages: u64) -> Result<GuardedStack, StackAllocationError> {
+    let mut stacks = STACKS.lock();
+    let slot = stacks
+        .iter_mut()
+        .find(|slot| slot.is_none())
+        .ok_or(StackAllocationError::TooManyStacks)?;
+
+    // one extra page for the guard, which is reserved but never mapped
+    let guard_start = VirtAddr::new(NEXT_STACK.fetch_add((pages + 1) * FRAME_SIZE, Ordering::Relaxed));
+    let guard_page = Page::containing_address(guard_start);
+    let bottom = guard_start + FRAME_SIZE;
+    let top = bottom + pages * FRAME_SIZE;
+    let stack_pages = Page::range(Page::containing_address(bottom), Page::containing_address(top));
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    for page in stack_pages {
+        if let Err(err) = super::map_page(page, flags) {
+            // give back what was already mapped - the virtual range just stays unused
+            for mapped in Page::range(Page::containing_address(bottom), page) {
+                unsafe { super::free_page(mapped).expect("freeing a just mapped stack page failed") };
+            }
+            return Err(StackAllocationError::MapFailed(err));
+        }
+    }
+
+    let stack = GuardedStack { name, guard_page, bottom, top };
+    *slot = Some(stack);
+    Ok(stack)
+}
+
+/**
+ * name of the stack whose guard page addr is in, ex: the faulting address in Cr2
+ * called from fault handlers, so it gives up instead of spinning if the registry is locked
+ */
+pub fn guard_page_owner(addr: VirtAddr) -> Option<&'static str> {
+    let stacks = STACKS.try_lock()?;
+    let page = Page::<Size4KiB>::containing_address(addr);
+    stacks
+        .iter()
+        .flatten()
+        .find(|stack| stack.guard_page == page)
+        .map(|stack| stack.name)
+}
+
+/**
+ * move rsp to the top of stack and call entry, abandoning the current stack
+ * # Safety
+ * nothing on the old stack may be referenced after the switch
+ */
+pub unsafe fn switch_to(stack: &GuardedStack, entry: extern "C" fn() -> !) -> ! {
+    // top is page aligned, so rsp is 16 byte aligned at the call like the ABI expects
+    core::arch::asm!(
+        "mov rsp, {top}",
+        // end of the frame pointer chain
+        "xor rbp, rbp",
+        "call {entry}",
+        top = in(reg) stack.top().as_u64(),
+        entry = in(reg) entry,
+        options(noreturn)
+    );
+}
+
+#[test_case]
+fn test_stack_is_mapped_with_guard_below(){
+    let stack = allocate("test stack", 2).expect("stack allocation failed");
+    assert!(super::translate_addr(stack.bottom()).is_some());
+    assert!(super::translate_addr(stack.top() - 1u64).is_some());
+    // the guard page is right below the bottom and never mapped
+    let guard = stack.bottom() - 1u64;
+    assert_eq!(super::translate_addr(guard), None);
+    assert_eq!(guard_page_owner(guard), Some("test stack"));
+    assert_eq!(guard_page_owner(stack.bottom()), None);
+}
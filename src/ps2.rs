@@ -0,0 +1,625 @@
+// Gregory Vincent
+// the 8042 PS/2 controller - the keyboard, and maybe a mouse, talk to the cpu through it
+// the controller takes commands on port 0x64, the devices behind it through port 0x60,
+// and everything comes back through port 0x60
+use core::future;
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU8, Ordering};
+use core::task::Poll;
+use core::time::Duration;
+use futures_util::task::AtomicWaker;
+use spin::{Mutex, MutexGuard};
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use crate::time;
+
+const DATA: u16 = 0x60;
+// status when read, commands when written
+const COMMAND: u16 = 0x64;
+
+// status register
+const OUTPUT_FULL: u8 = 1 << 0;
+const INPUT_FULL: u8 = 1 << 1;
+
+// controller commands
+const READ_CONFIG: u8 = 0x20;
+const WRITE_CONFIG: u8 = 0x60;
+const DISABLE_SECOND_PORT: u8 = 0xa7;
+const ENABLE_SECOND_PORT: u8 = 0xa8;
+const TEST_SECOND_PORT: u8 = 0xa9;
+const SELF_TEST: u8 = 0xaa;
+const TEST_FIRST_PORT: u8 = 0xab;
+const DISABLE_FIRST_PORT: u8 = 0xad;
+const ENABLE_FIRST_PORT: u8 = 0xae;
+// the next byte written to port 0x60 goes to the second port's device
+const WRITE_SECOND_PORT: u8 = 0xd4;
+const SELF_TEST_PASSED: u8 = 0x55;
+const PORT_TEST_PASSED: u8 = 0x00;
+
+// configuration byte
+const FIRST_PORT_IRQ: u8 = 1 << 0;
+const SECOND_PORT_IRQ: u8 = 1 << 1;
+const SECOND_PORT_CLOCK_DISABLED: u8 = 1 << 5;
+// turn the keyboard's scancode set 2 into set 1 on the way through
+const TRANSLATION: u8 = 1 << 6;
+
+// keyboard commands
+const SET_LEDS: u8 = 0xed;
+const SET_TYPEMATIC: u8 = 0xf3;
+const ENABLE_SCANNING: u8 = 0xf4;
+const RESET: u8 = 0xff;
+const ACK: u8 = 0xfa;
+const RESEND: u8 = 0xfe;
+const RESET_PASSED: u8 = 0xaa;
+const RETRIES: usize = 3;
+
+// the slowest devices take a few hundred ms to reset
+const TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Error{
+    // init hasn't found a working controller
+    NotInitialized,
+    // a task's command is still waiting on its answer, try again later
+    Busy,
+    // nothing answered in time, ex: no controller at all on a legacy free machine
+    Timeout,
+    SelfTestFailed(u8),
+    PortTestFailed(u8),
+    // the device kept asking for the byte again, or answered with something else
+    Unexpected(u8),
+}
+
+// what init found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ps2Controller{
+    pub keyboard: bool,
+    // the second port exists and passed its test, usually for a mouse
+    pub second_port: bool,
+}
+
+// which device a byte on port 0x60 came from, the controller's own answers come in on the first
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Ps2Port{
+    First,
+    Second,
+}
+
+/**
+ * bytes on port 0x60 that answer a command instead of being keyboard or mouse input
+ * with interrupts on, the irq handlers read port 0x60 first and hand them over through take_response
+ * EXPECTING holds what's wanted and, from EXPECT_SECOND_PORT up, which port it has to come from
+ */
+const EXPECT_NOTHING: u8 = 0;
+// only an ACK or RESEND, anything else is input that happened to arrive first
+const EXPECT_ACK: u8 = 1;
+const EXPECT_ANY: u8 = 2;
+const EXPECT_SECOND_PORT: u8 = 1 << 4;
+static EXPECTING: AtomicU8 = AtomicU8::new(EXPECT_NOTHING);
+
+fn expecting(what: u8, port: Ps2Port) -> u8 {
+    match port {
+        Ps2Port::First => what,
+        Ps2Port::Second => what | EXPECT_SECOND_PORT,
+    }
+}
+// 0 when empty, otherwise RESPONSE_VALID | the byte
+static RESPONSE: AtomicU16 = AtomicU16::new(0);
+const RESPONSE_VALID: u16 = 1 << 8;
+// a task waiting on RESPONSE - see response_async
+static RESPONSE_WAKER: AtomicWaker = AtomicWaker::new();
+
+/**
+ * command sequences can't be interleaved, only ever locked outside interrupt handlers
+ * a task can hold it while it waits for an answer, so after boot it's only ever
+ * tried - spinning on it would keep that task from ever running again
+ */
+static COMMANDS: Mutex<()> = Mutex::new(());
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+static SECOND_PORT: AtomicBool = AtomicBool::new(false);
+// the mouse driver turned the second port on
+static SECOND_PORT_ENABLED: AtomicBool = AtomicBool::new(false);
+
+fn status() -> u8 {
+    unsafe { Port::new(COMMAND).read() }
+}
+
+// wait for the controller to take the last byte before sending another
+fn write_ready() -> Result<(), Ps2Error> {
+    let start = time::now();
+    while status() & INPUT_FULL != 0 {
+        if start.elapsed() > TIMEOUT {
+            return Err(Ps2Error::Timeout);
+        }
+        core::hint::spin_loop();
+    }
+    Ok(())
+}
+
+fn write_command(command: u8) -> Result<(), Ps2Error> {
+    write_ready()?;
+    unsafe { Port::new(COMMAND).write(command) };
+    Ok(())
+}
+
+fn write_data(data: u8) -> Result<(), Ps2Error> {
+    write_ready()?;
+    unsafe { Port::new(DATA).write(data) };
+    Ok(())
+}
+
+/**
+ * called by the keyboard and mouse irq handlers with the byte they read and the port it came in on
+ * true if it answers a command to that port, and shouldn't be treated as input
+ * ex: a mouse packet byte of 0xfa isn't the keyboard's ACK
+ */
+pub(crate) fn take_response(port: Ps2Port, byte: u8) -> bool {
+    let expected = EXPECTING.load(Ordering::SeqCst);
+    let wanted = if expected == expecting(EXPECT_ANY, port) {
+        true
+    } else if expected == expecting(EXPECT_ACK, port) {
+        byte == ACK || byte == RESEND
+    } else {
+        false
+    };
+    if wanted {
+        EXPECTING.store(EXPECT_NOTHING, Ordering::SeqCst);
+        RESPONSE.store(RESPONSE_VALID | byte as u16, Ordering::SeqCst);
+        RESPONSE_WAKER.wake();
+    }
+    wanted
+}
+
+// the lock for a command sent straight away, Busy while a task is partway through one
+fn try_lock_commands() -> Result<MutexGuard<'static, ()>, Ps2Error> {
+    COMMANDS.try_lock().ok_or(Ps2Error::Busy)
+}
+
+// the lock for a task, gives the other tasks a turn until it's free
+async fn lock_commands() -> MutexGuard<'static, ()> {
+    loop {
+        if let Some(commands) = COMMANDS.try_lock() {
+            return commands;
+        }
+        crate::task::yield_now().await;
+    }
+}
+
+/**
+ * send something that gets a byte back, and wait for it
+ * interrupts off - poll port 0x60 ourselves, interrupts on - the irq handler reads it
+ */
+fn exchange(send: impl FnOnce() -> Result<(), Ps2Error>, expecting: u8) -> Result<u8, Ps2Error> {
+    RESPONSE.store(0, Ordering::SeqCst);
+    EXPECTING.store(expecting, Ordering::SeqCst);
+    let result = send().and_then(|()| read_response());
+    EXPECTING.store(EXPECT_NOTHING, Ordering::SeqCst);
+    result
+}
+
+fn read_response() -> Result<u8, Ps2Error> {
+    let start = time::now();
+    loop {
+        if interrupts::are_enabled() {
+            let response = RESPONSE.load(Ordering::SeqCst);
+            if response & RESPONSE_VALID != 0 {
+                return Ok(response as u8);
+            }
+        } else if status() & OUTPUT_FULL != 0 {
+            return Ok(unsafe { Port::new(DATA).read() });
+        }
+        if start.elapsed() > TIMEOUT {
+            return Err(Ps2Error::Timeout);
+        }
+        core::hint::spin_loop();
+    }
+}
+
+// a controller command that answers with a byte
+fn command_response(command: u8) -> Result<u8, Ps2Error> {
+    exchange(|| write_command(command), expecting(EXPECT_ANY, Ps2Port::First))
+}
+
+fn read_config() -> Result<u8, Ps2Error> {
+    command_response(READ_CONFIG)
+}
+
+fn write_config(config: u8) -> Result<(), Ps2Error> {
+    write_command(WRITE_CONFIG)?;
+    write_data(config)
+}
+
+/**
+ * the byte on port 0x60 for an irq handler, None if there isn't one anymore
+ * ex: flush threw it away while interrupts were off, and the irq came in after
+ */
+pub(crate) fn read_data() -> Option<u8> {
+    if status() & OUTPUT_FULL == 0 {
+        return None;
+    }
+    Some(unsafe { Port::new(DATA).read() })
+}
+
+// throw away whatever the devices sent before we were listening
+fn flush(){
+    while status() & OUTPUT_FULL != 0 {
+        unsafe { Port::<u8>::new(DATA).read() };
+    }
+}
+
+// a byte for a device, sent again while it asks for it
+fn device_command(second_port: bool, byte: u8) -> Result<(), Ps2Error> {
+    let send = || {
+        if second_port {
+            write_command(WRITE_SECOND_PORT)?;
+        }
+        write_data(byte)
+    };
+    let port = if second_port { Ps2Port::Second } else { Ps2Port::First };
+    for _ in 0..RETRIES {
+        match exchange(send, expecting(EXPECT_ACK, port))? {
+            ACK => return Ok(()),
+            RESEND => continue,
+            other => return Err(Ps2Error::Unexpected(other)),
+        }
+    }
+    Err(Ps2Error::Unexpected(RESEND))
+}
+
+fn keyboard_command(byte: u8) -> Result<(), Ps2Error> {
+    device_command(false, byte)
+}
+
+/**
+ * self test the controller and its ports, reset the keyboard and turn on its irq
+ * translation follows the keyboard's scancode set - see task::keyboard::set_config
+ * interrupts have to be off, and the irq handlers registered so nothing is lost after
+ */
+pub fn init() -> Result<Ps2Controller, Ps2Error> {
+    let _commands = COMMANDS.lock();
+    // nothing may send anything while we're testing
+    write_command(DISABLE_FIRST_PORT)?;
+    write_command(DISABLE_SECOND_PORT)?;
+    flush();
+
+    let mut config = read_config()?;
+    // a second port's clock only turns on when it's enabled if there is one
+    let maybe_second_port = config & SECOND_PORT_CLOCK_DISABLED != 0;
+    // no irqs until everything's set up, their bytes are read by polling until then
+    config &= !(FIRST_PORT_IRQ | SECOND_PORT_IRQ);
+    write_config(config)?;
+
+    match command_response(SELF_TEST)? {
+        SELF_TEST_PASSED => {}
+        other => return Err(Ps2Error::SelfTestFailed(other)),
+    }
+    // the self test resets the configuration on some controllers
+    write_config(config)?;
+
+    let mut second_port = false;
+    if maybe_second_port {
+        write_command(ENABLE_SECOND_PORT)?;
+        second_port = read_config()? & SECOND_PORT_CLOCK_DISABLED == 0;
+        write_command(DISABLE_SECOND_PORT)?;
+    }
+    match command_response(TEST_FIRST_PORT)? {
+        PORT_TEST_PASSED => {}
+        other => return Err(Ps2Error::PortTestFailed(other)),
+    }
+    if second_port {
+        second_port = command_response(TEST_SECOND_PORT)? == PORT_TEST_PASSED;
+    }
+
+    write_command(ENABLE_FIRST_PORT)?;
+    // a machine can have a controller and no keyboard plugged in
+    let keyboard = reset_keyboard().is_ok();
+    if keyboard {
+        // a keyboard that won't take these still types, at its own rate and without lights
+        if let Err(err) = set_typematic_locked(DEFAULT_TYPEMATIC_DELAY, DEFAULT_TYPEMATIC_RATE) {
+            crate::serial_println!("PS/2 keyboard ignored the typematic rate ({:?})", err);
+        }
+        if let Err(err) = set_leds_locked(Leds::default()) {
+            crate::serial_println!("PS/2 keyboard ignored its lights ({:?})", err);
+        }
+    }
+
+    config |= FIRST_PORT_IRQ;
+    if translation_wanted() {
+        config |= TRANSLATION;
+    } else {
+        config &= !TRANSLATION;
+    }
+    write_config(config)?;
+    // only once the keyboard's irq is back on
+    SECOND_PORT.store(second_port, Ordering::SeqCst);
+    INITIALIZED.store(true, Ordering::SeqCst);
+    Ok(Ps2Controller { keyboard, second_port })
+}
+
+fn reset_keyboard() -> Result<(), Ps2Error> {
+    keyboard_command(RESET)?;
+    match read_response()? {
+        RESET_PASSED => {}
+        other => return Err(Ps2Error::Unexpected(other)),
+    }
+    keyboard_command(ENABLE_SCANNING)
+}
+
+pub fn is_initialized() -> bool {
+    INITIALIZED.load(Ordering::SeqCst)
+}
+
+// whether init found a working second port - see Ps2Controller
+pub fn has_second_port() -> bool {
+    SECOND_PORT.load(Ordering::SeqCst)
+}
+
+/**
+ * turn the second port on, with its irq off - for the mouse driver to set up the device
+ * interrupts have to be off until set_second_port_irq, device answers are polled
+ */
+pub(crate) fn enable_second_port() -> Result<(), Ps2Error> {
+    if !has_second_port() {
+        return Err(Ps2Error::NotInitialized);
+    }
+    let _commands = COMMANDS.lock();
+    write_command(ENABLE_SECOND_PORT)?;
+    SECOND_PORT_ENABLED.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+// back off, ex: the device on it didn't answer
+pub(crate) fn disable_second_port() -> Result<(), Ps2Error> {
+    let _commands = COMMANDS.lock();
+    SECOND_PORT_ENABLED.store(false, Ordering::SeqCst);
+    write_command(DISABLE_SECOND_PORT)
+}
+
+// once the device is set up and something is listening on irq 12
+pub(crate) fn set_second_port_irq(on: bool) -> Result<(), Ps2Error> {
+    let _commands = COMMANDS.lock();
+    let config = read_config()?;
+    write_config(if on { config | SECOND_PORT_IRQ } else { config & !SECOND_PORT_IRQ })
+}
+
+// a byte for the device on the second port, waits for its ACK
+pub(crate) fn second_port_command(byte: u8) -> Result<(), Ps2Error> {
+    let _commands = COMMANDS.lock();
+    device_command(true, byte)
+}
+
+// the next byte a device sends after its ACK, ex: the mouse's id
+pub(crate) fn read_device_byte() -> Result<u8, Ps2Error> {
+    let _commands = COMMANDS.lock();
+    read_response()
+}
+
+fn translation_wanted() -> bool {
+    use crate::task::keyboard::{config, ScancodeSet};
+    config().scancode_set == ScancodeSet::Set1
+}
+
+/**
+ * have the controller translate to scancode set 1, or pass set 2 through untouched
+ * the keyboard itself always sends set 2
+ * the keyboard's port is off while the config is read and written back, like in init -
+ * a scancode in between would otherwise be taken for the config byte, and a key
+ * pressed right then is lost instead
+ */
+pub fn set_translation(on: bool) -> Result<(), Ps2Error> {
+    if !is_initialized() {
+        return Err(Ps2Error::NotInitialized);
+    }
+    let _commands = try_lock_commands()?;
+    // polled, so the irq handler can't read the config byte first
+    interrupts::without_interrupts(|| {
+        // a mouse byte would be just as wrong
+        let second_port = SECOND_PORT_ENABLED.load(Ordering::SeqCst);
+        let result = write_command(DISABLE_FIRST_PORT)
+            .and_then(|()| if second_port { write_command(DISABLE_SECOND_PORT) } else { Ok(()) })
+            .and_then(|()| {
+                flush();
+                read_config()
+            })
+            .and_then(|config| write_config(if on { config | TRANSLATION } else { config & !TRANSLATION }));
+        // back on even if the config couldn't be changed
+        let mut enabled = write_command(ENABLE_FIRST_PORT);
+        if second_port {
+            enabled = enabled.and(write_command(ENABLE_SECOND_PORT));
+        }
+        result.and(enabled)
+    })
+}
+
+// the keyboard's lock lights
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Leds{
+    pub scroll_lock: bool,
+    pub num_lock: bool,
+    pub caps_lock: bool,
+}
+
+// the byte the keyboard's SET_LEDS command takes, bits above the low 3 are ignored
+impl Leds{
+    pub(crate) fn bits(&self) -> u8 {
+        self.scroll_lock as u8 | (self.num_lock as u8) << 1 | (self.caps_lock as u8) << 2
+    }
+
+    pub(crate) fn from_bits(bits: u8) -> Leds {
+        Leds { scroll_lock: bits & 1 != 0, num_lock: bits & 2 != 0, caps_lock: bits & 4 != 0 }
+    }
+}
+
+/**
+ * waits for the keyboard to answer each byte, up to TIMEOUT if it doesn't - from a task,
+ * set_leds_async lets the others run in the meantime
+ */
+pub fn set_leds(leds: Leds) -> Result<(), Ps2Error> {
+    if !is_initialized() {
+        return Err(Ps2Error::NotInitialized);
+    }
+    let _commands = try_lock_commands()?;
+    set_leds_locked(leds)
+}
+
+// set_leds for tasks, interrupts have to be on for the answers to come in
+pub async fn set_leds_async(leds: Leds) -> Result<(), Ps2Error> {
+    if !is_initialized() {
+        return Err(Ps2Error::NotInitialized);
+    }
+    let _commands = lock_commands().await;
+    keyboard_command_async(SET_LEDS).await?;
+    keyboard_command_async(leds.bits()).await
+}
+
+// keyboard_command, waiting for the answer without holding up other tasks
+async fn keyboard_command_async(byte: u8) -> Result<(), Ps2Error> {
+    for _ in 0..RETRIES {
+        RESPONSE.store(0, Ordering::SeqCst);
+        EXPECTING.store(expecting(EXPECT_ACK, Ps2Port::First), Ordering::SeqCst);
+        let result = match write_data(byte) {
+            Ok(()) => response_async().await,
+            Err(err) => Err(err),
+        };
+        EXPECTING.store(EXPECT_NOTHING, Ordering::SeqCst);
+        match result? {
+            ACK => return Ok(()),
+            RESEND => continue,
+            other => return Err(Ps2Error::Unexpected(other)),
+        }
+    }
+    Err(Ps2Error::Unexpected(RESEND))
+}
+
+fn wake_response_waiter(){
+    RESPONSE_WAKER.wake();
+}
+
+// read_response for tasks - woken by take_response, or by a timer once TIMEOUT is up
+async fn response_async() -> Result<u8, Ps2Error> {
+    let start = time::now();
+    let timeout = time::set_timeout(TIMEOUT, wake_response_waiter).ok();
+    let result = future::poll_fn(|context| {
+        let response = RESPONSE.load(Ordering::SeqCst);
+        if response & RESPONSE_VALID != 0 {
+            return Poll::Ready(Ok(response as u8));
+        }
+        if start.elapsed() > TIMEOUT {
+            return Poll::Ready(Err(Ps2Error::Timeout));
+        }
+        RESPONSE_WAKER.register(context.waker());
+        // no timer to wake us, check again on the next turn
+        if timeout.is_none() {
+            context.waker().wake_by_ref();
+        }
+        // an answer that came before register wouldn't have woken us
+        match RESPONSE.load(Ordering::SeqCst) {
+            response if response & RESPONSE_VALID != 0 => Poll::Ready(Ok(response as u8)),
+            _ => Poll::Pending,
+        }
+    })
+    .await;
+    if let Some(timeout) = timeout {
+        time::cancel(timeout);
+    }
+    result
+}
+
+fn set_leds_locked(leds: Leds) -> Result<(), Ps2Error> {
+    keyboard_command(SET_LEDS)?;
+    keyboard_command(leds.bits())
+}
+
+// what the keyboard resets to - half a second, then about 10 repeats a second
+const DEFAULT_TYPEMATIC_DELAY: Duration = Duration::from_millis(500);
+const DEFAULT_TYPEMATIC_RATE: u32 = 10;
+
+// the typematic byte - bits 0-4 pick the repeat rate, bits 5-6 the delay before repeating starts
+fn typematic_byte(delay: Duration, repeats_per_second: u32) -> u8 {
+    // 250, 500, 750 or 1000 ms
+    let delay = ((delay.as_millis() as u64 + 125) / 250).clamp(1, 4) - 1;
+    // repeats every (8 + low 3 bits) * 2^(bits 3-4) * 4.17 ms, 30 down to 2 a second
+    let period_us = 1_000_000 / repeats_per_second.max(1) as u64;
+    let rate = (0..32u64)
+        .min_by_key(|rate| {
+            let period = (8 + (rate & 7)) * (1 << ((rate >> 3) & 3)) * 4170;
+            (period as i64 - period_us as i64).abs()
+        })
+        .unwrap_or(0);
+    (delay << 5) as u8 | rate as u8
+}
+
+/**
+ * how long a key has to be held before it repeats, and how fast it repeats then
+ * rounded to what the keyboard can do - 250 to 1000 ms, 2 to 30 repeats a second
+ */
+pub fn set_typematic(delay: Duration, repeats_per_second: u32) -> Result<(), Ps2Error> {
+    if !is_initialized() {
+        return Err(Ps2Error::NotInitialized);
+    }
+    let _commands = try_lock_commands()?;
+    set_typematic_locked(delay, repeats_per_second)
+}
+
+fn set_typematic_locked(delay: Duration, repeats_per_second: u32) -> Result<(), Ps2Error> {
+    keyboard_command(SET_TYPEMATIC)?;
+    keyboard_command(typematic_byte(delay, repeats_per_second))
+}
+
+#[test_case]
+fn test_typematic_byte(){
+    // 500 ms, then 10 a second
+    assert_eq!(typematic_byte(Duration::from_millis(500), 10), 0x2c);
+    // fastest and slowest
+    assert_eq!(typematic_byte(Duration::from_millis(250), 30), 0x00);
+    assert_eq!(typematic_byte(Duration::from_millis(1000), 2), 0x7f);
+}
+
+#[test_case]
+fn test_led_bits(){
+    let leds = Leds { num_lock: true, caps_lock: true, ..Leds::default() };
+    assert_eq!(leds.bits(), 0b110);
+    assert_eq!(Leds::from_bits(leds.bits()), leds);
+    assert_eq!(Leds::from_bits(0x80), Leds::default());
+}
+
+#[test_case]
+fn test_controller_is_initialized(){
+    assert!(is_initialized());
+    assert_eq!(set_leds(Leds { caps_lock: true, ..Leds::default() }), Ok(()));
+    assert_eq!(set_leds(Leds::default()), Ok(()));
+}
+
+#[test_case]
+fn test_leds_from_a_task(){
+    use crate::task::{Executor, Task};
+    static DONE: AtomicBool = AtomicBool::new(false);
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async {
+        assert_eq!(set_leds_async(Leds { num_lock: true, ..Leds::default() }).await, Ok(()));
+        assert_eq!(set_leds_async(Leds::default()).await, Ok(()));
+        DONE.store(true, Ordering::SeqCst);
+    }));
+    // the lock is held while the task waits for an answer
+    executor.spawn(Task::new(async {
+        if COMMANDS.try_lock().is_none() {
+            assert_eq!(set_leds(Leds::default()), Err(Ps2Error::Busy));
+        }
+    }));
+    executor.run_until_complete();
+    assert!(DONE.load(Ordering::SeqCst));
+}
+
+#[test_case]
+fn test_responses_only_come_from_the_commanded_port(){
+    interrupts::without_interrupts(|| {
+        EXPECTING.store(expecting(EXPECT_ACK, Ps2Port::First), Ordering::SeqCst);
+        // a mouse packet byte that happens to look like an ACK
+        assert!(!take_response(Ps2Port::Second, ACK));
+        assert!(take_response(Ps2Port::First, ACK));
+        EXPECTING.store(expecting(EXPECT_ANY, Ps2Port::Second), Ordering::SeqCst);
+        assert!(!take_response(Ps2Port::First, 0x1e));
+        assert!(take_response(Ps2Port::Second, 0x00));
+        assert_eq!(EXPECTING.load(Ordering::SeqCst), EXPECT_NOTHING);
+        RESPONSE.store(0, Ordering::SeqCst);
+    });
+}
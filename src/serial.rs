@@ -15,6 +15,70 @@ lazy_static! {
 }
 // macros to make serial port more usable
 
+/**
+ * a copy of what goes out over serial, kept in memory while capturing is on
+ * ex: an exception test checking what the handler reported
+ * fixed size since it has to work without a heap, anything past the end is left out
+ * only built for tests, ex: cargo test --features exception-tests
+ */
+#[cfg(any(test, feature = "exception-tests"))]
+const CAPTURE_SIZE: usize = 4096;
+
+#[cfg(any(test, feature = "exception-tests"))]
+struct Capture{
+    on: bool,
+    bytes: [u8; CAPTURE_SIZE],
+    len: usize,
+}
+
+#[cfg(any(test, feature = "exception-tests"))]
+impl core::fmt::Write for Capture{
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let free = CAPTURE_SIZE - self.len;
+        let len = s.len().min(free);
+        self.bytes[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+#[cfg(any(test, feature = "exception-tests"))]
+static CAPTURE: Mutex<Capture> = Mutex::new(Capture { on: false, bytes: [0; CAPTURE_SIZE], len: 0 });
+
+// start over with an empty capture
+#[cfg(any(test, feature = "exception-tests"))]
+pub fn start_capture(){
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(||{
+        let mut capture = CAPTURE.lock();
+        capture.on = true;
+        capture.len = 0;
+    });
+}
+
+// stop capturing, what was captured so far stays readable through with_captured
+#[cfg(any(test, feature = "exception-tests"))]
+pub fn stop_capture(){
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| CAPTURE.lock().on = false);
+}
+
+// f gets everything captured since start_capture - it can't print to serial itself, that would deadlock
+#[cfg(any(test, feature = "exception-tests"))]
+pub fn with_captured<R>(f: impl FnOnce(&str) -> R) -> R {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(||{
+        let capture = CAPTURE.lock();
+        let bytes = &capture.bytes[..capture.len];
+        // the end can be cut off in the middle of a character
+        let text = match core::str::from_utf8(bytes) {
+            Ok(text) => text,
+            Err(err) => core::str::from_utf8(&bytes[..err.valid_up_to()]).unwrap_or(""),
+        };
+        f(text)
+    })
+}
+
 //under the hood print fn each macro calls
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments){
@@ -26,6 +90,27 @@ pub fn _print(args: ::core::fmt::Arguments){
             .lock()
             .write_fmt(args)
             .expect("Serial printing should not have failed.");
+        #[cfg(any(test, feature = "exception-tests"))]
+        {
+            let mut capture = CAPTURE.lock();
+            if capture.on {
+                let _ = capture.write_fmt(args);
+            }
+        }
+    });
+}
+
+/**
+ * _print for code that can interrupt a print, ex: an exception handler
+ * the output is dropped if the port is already locked, waiting on it would never end
+ */
+pub fn try_print(args: ::core::fmt::Arguments){
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(||{
+        if let Some(mut serial) = SERIAL1.try_lock() {
+            let _ = serial.write_fmt(args);
+        }
     });
 }
 
@@ -47,3 +132,18 @@ macro_rules! serial_println {
         concat!($fmt, "\n"), $($arg)*));
 }
 
+// serial_println with the time in front, ex: [2024-02-29 13:37:42] heap initialized
+#[macro_export]
+macro_rules! serial_log {
+    ($($arg:tt)*) => ($crate::serial_println!(
+        "[{}] {}", $crate::time::Timestamp, format_args!($($arg)*)));
+}
+
+#[test_case]
+fn test_capture(){
+    start_capture();
+    serial_print!("captured ");
+    stop_capture();
+    serial_print!("not captured ");
+    assert!(with_captured(|text| text == "captured "));
+}
@@ -0,0 +1,142 @@
+// Gregory Vincent
+// kernel symbol table - turns a code address into function+offset for backtraces
+use core::fmt;
+
+/**
+ * space for the table is reserved in its own section of the kernel ELF
+ * after linking, tools/embed_symbols.py fills it in from the ELF's .symtab
+ * (the cargo runner does this before every run, see tools/runner.sh)
+ * it can't be generated at compile time since embedding it would move the addresses it describes
+ *
+ * layout, all little endian:
+ *   magic       [u8; 8]  "KSYMTAB\0"
+ *   count       u32      number of entries, 0 until the table is embedded
+ *   names_size  u32      bytes of names after the entries
+ *   entries     count * { address: u64, size: u32, name_offset: u32 } sorted by address
+ *   names       names_size bytes, each name ends in a nul
+ */
+const SYMBOL_TABLE_SIZE: usize = 512 * 1024;
+const MAGIC: &[u8; 8] = b"KSYMTAB\0";
+const HEADER_SIZE: usize = 16;
+const ENTRY_SIZE: usize = 16;
+
+// global_asm so the compiler can't assume the table stays the zeros it was assembled with
+core::arch::global_asm!(
+    ".pushsection .ksymtab, \"a\", @progbits",
+    ".balign 8",
+    ".global KERNEL_SYMBOL_TABLE",
+    "KERNEL_SYMBOL_TABLE:",
+    ".ascii \"KSYMTAB\\0\"",
+    ".zero {size} - 8",
+    ".popsection",
+    size = const SYMBOL_TABLE_SIZE,
+);
+
+extern "C" {
+    static KERNEL_SYMBOL_TABLE: [u8; SYMBOL_TABLE_SIZE];
+}
+
+// a function name and how far into the function an address is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Symbol{
+    pub name: &'static str,
+    pub offset: u64,
+}
+
+impl fmt::Display for Symbol{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}+{:#x}", self.name, self.offset)
+    }
+}
+
+fn table() -> &'static [u8] {
+    unsafe { &KERNEL_SYMBOL_TABLE }
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    let mut value = [0; 4];
+    value.copy_from_slice(&bytes[at..at + 4]);
+    u32::from_le_bytes(value)
+}
+
+fn read_u64(bytes: &[u8], at: usize) -> u64 {
+    let mut value = [0; 8];
+    value.copy_from_slice(&bytes[at..at + 8]);
+    u64::from_le_bytes(value)
+}
+
+// entries and names, None if the table was never embedded or doesn't fit its space
+fn contents() -> Option<(&'static [u8], &'static [u8])> {
+    let table = table();
+    if &table[..8] != MAGIC {
+        return None;
+    }
+    let count = read_u32(table, 8) as usize;
+    let names_size = read_u32(table, 12) as usize;
+    let entries_end = HEADER_SIZE.checked_add(count.checked_mul(ENTRY_SIZE)?)?;
+    let names_end = entries_end.checked_add(names_size)?;
+    if count == 0 || names_end > table.len() {
+        return None;
+    }
+    Some((&table[HEADER_SIZE..entries_end], &table[entries_end..names_end]))
+}
+
+// false when the kernel was started without going through tools/embed_symbols.py
+pub fn is_loaded() -> bool {
+    contents().is_some()
+}
+
+/**
+ * the function containing addr
+ * for a return address, look up address - 1 - a call as the last instruction of a function
+ * returns to the start of whatever comes next
+ */
+pub fn lookup(addr: u64) -> Option<Symbol> {
+    let (entries, names) = contents()?;
+    let entry_address = |index: usize| read_u64(entries, index * ENTRY_SIZE);
+    let count = entries.len() / ENTRY_SIZE;
+    // binary search for the last entry starting at or before addr
+    let (mut low, mut high) = (0, count);
+    while low < high {
+        let middle = (low + high) / 2;
+        if entry_address(middle) <= addr {
+            low = middle + 1;
+        } else {
+            high = middle;
+        }
+    }
+    let index = low.checked_sub(1)?;
+    let entry = index * ENTRY_SIZE;
+    let start = entry_address(index);
+    let size = read_u32(entries, entry + 8) as u64;
+    // a size of 0 means the ELF didn't record one, trust it up to the next symbol
+    if size != 0 && addr - start >= size {
+        return None;
+    }
+    let name_offset = read_u32(entries, entry + 12) as usize;
+    let name = names.get(name_offset..)?;
+    let name_len = name.iter().position(|&byte| byte == 0)?;
+    let name = core::str::from_utf8(&name[..name_len]).ok()?;
+    Some(Symbol { name, offset: addr - start })
+}
+
+#[cfg(test)]
+#[inline(never)]
+fn lookup_target() -> u64 {
+    42
+}
+
+#[test_case]
+fn test_lookup_finds_function(){
+    // the test runner goes through tools/runner.sh, so the table is there
+    assert!(is_loaded());
+    let addr = lookup_target as *const () as u64;
+    let symbol = lookup(addr + 1).expect("no symbol for lookup_target");
+    assert!(symbol.name.ends_with("symbols::lookup_target"));
+    assert_eq!(symbol.offset, 1);
+}
+
+#[test_case]
+fn test_lookup_outside_kernel(){
+    assert_eq!(lookup(0), None);
+}
@@ -0,0 +1,82 @@
+// Gregory Vincent
+// cooperative multitasking with async/await - a task is a future that gets polled
+// until it's done, giving the cpu back every time it has to wait for something
+// needs the heap, every task is boxed
+use alloc::boxed::Box;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll};
+
+// runs tasks, sleeping the cpu when none of them can make progress
+pub mod executor;
+// bytes from an interrupt handler to the task reading them
+pub mod byte_queue;
+// scancodes from the keyboard interrupt as an async stream
+pub mod keyboard;
+// movement and button events from the PS/2 mouse
+pub mod mouse;
+
+pub use executor::Executor;
+
+// tells tasks apart for the executor and their wakers, never reused
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TaskId(u64);
+
+impl TaskId{
+    fn new() -> TaskId {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/**
+ * a future with nothing left to return, ex: Task::new(print_keypresses())
+ * pinned on the heap, async fns can hold references into their own state
+ */
+pub struct Task{
+    id: TaskId,
+    future: Pin<Box<dyn Future<Output = ()>>>,
+}
+
+impl Task{
+    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+        Task {
+            id: TaskId::new(),
+            future: Box::pin(future),
+        }
+    }
+
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    fn poll(&mut self, context: &mut Context) -> Poll<()> {
+        self.future.as_mut().poll(context)
+    }
+}
+
+/**
+ * give the other tasks a turn - wakes itself right away, so the task
+ * goes to the back of the queue instead of waiting for anything
+ */
+pub async fn yield_now(){
+    struct YieldNow{
+        yielded: bool,
+    }
+
+    impl Future for YieldNow{
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+            if self.yielded {
+                return Poll::Ready(());
+            }
+            self.yielded = true;
+            context.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    YieldNow { yielded: false }.await
+}
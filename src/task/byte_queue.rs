@@ -0,0 +1,80 @@
+// bytes from an interrupt handler to the one task reading them, ex: scancodes
+// the handler pushes and wakes the task, the task pops - neither ever blocks
+use core::task::{Context, Poll};
+use crossbeam_queue::ArrayQueue;
+use futures_util::task::AtomicWaker;
+
+pub struct ByteQueue{
+    bytes: ArrayQueue<u8>,
+    // the task waiting on the queue, woken whenever a byte arrives
+    waker: AtomicWaker,
+}
+
+impl ByteQueue{
+    // allocates, so it has to be made outside the interrupt handler
+    pub fn new(size: usize) -> ByteQueue {
+        ByteQueue { bytes: ArrayQueue::new(size), waker: AtomicWaker::new() }
+    }
+
+    /**
+     * for the interrupt handler - never blocks or allocates
+     * false if the queue was full and the byte got dropped
+     */
+    pub fn push(&self, byte: u8) -> bool {
+        if self.bytes.push(byte).is_err() {
+            return false;
+        }
+        self.waker.wake();
+        true
+    }
+
+    // the next byte, or Pending until push wakes the task
+    pub fn poll_pop(&self, context: &mut Context) -> Poll<u8> {
+        // the common case, no need to touch the waker
+        if let Some(byte) = self.bytes.pop() {
+            return Poll::Ready(byte);
+        }
+        self.waker.register(context.waker());
+        // a byte that arrived before register wouldn't have woken us, check again
+        match self.bytes.pop() {
+            Some(byte) => {
+                self.waker.take();
+                Poll::Ready(byte)
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[test_case]
+fn test_bytes_come_out_in_order(){
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use super::{Executor, Task};
+    static DONE: AtomicBool = AtomicBool::new(false);
+    let queue = Arc::new(ByteQueue::new(4));
+    let mut executor = Executor::new();
+    let reader = queue.clone();
+    executor.spawn(Task::new(async move {
+        for expected in [0x1e, 0x9e, 0x30] {
+            assert_eq!(core::future::poll_fn(|context| reader.poll_pop(context)).await, expected);
+        }
+        DONE.store(true, Ordering::SeqCst);
+    }));
+    // as if an interrupt handler got them, one at a time
+    executor.spawn(Task::new(async move {
+        for byte in [0x1e, 0x9e, 0x30] {
+            assert!(queue.push(byte));
+            super::yield_now().await;
+        }
+    }));
+    executor.run_until_complete();
+    assert!(DONE.load(Ordering::SeqCst));
+}
+
+#[test_case]
+fn test_full_queue_drops_bytes(){
+    let queue = ByteQueue::new(4);
+    let dropped = (0..7).filter(|&byte| !queue.push(byte)).count();
+    assert_eq!(dropped, 3);
+}
@@ -0,0 +1,288 @@
+// polls tasks when they're woken, and only then
+// wakers push their task's id onto a lock free queue, so an interrupt handler can
+// wake a task without taking any lock the interrupted code might hold
+use alloc::{collections::BTreeMap, sync::Arc, task::Wake, vec::Vec};
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+use crossbeam_queue::ArrayQueue;
+use x86_64::instructions::interrupts;
+use super::{Task, TaskId};
+
+// woken tasks waiting to be polled, at most one entry per wake
+const TASK_QUEUE_SIZE: usize = 100;
+
+// shared by the executor and every waker it hands out
+struct TaskQueue{
+    ids: ArrayQueue<TaskId>,
+    // a wake didn't fit in ids, so every task gets polled - an extra poll is harmless, a lost one isn't
+    overflowed: AtomicBool,
+}
+
+impl TaskQueue{
+    fn push(&self, id: TaskId){
+        if self.ids.push(id).is_err() {
+            self.overflowed.store(true, Ordering::SeqCst);
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.ids.is_empty() && !self.overflowed.load(Ordering::SeqCst)
+    }
+}
+
+pub struct Executor{
+    tasks: BTreeMap<TaskId, Task>,
+    task_queue: Arc<TaskQueue>,
+    // one waker per task, instead of a new allocation every poll
+    waker_cache: BTreeMap<TaskId, Arc<TaskWaker>>,
+    /**
+     * wakers of finished tasks that something else still holds, ex: in an AtomicWaker
+     * kept until the executor's is the last reference, so dropping a waker in an
+     * interrupt handler never frees it there
+     */
+    retired_wakers: Vec<Arc<TaskWaker>>,
+}
+
+impl Default for Executor{
+    fn default() -> Executor {
+        Executor::new()
+    }
+}
+
+impl Executor{
+    pub fn new() -> Executor {
+        Executor {
+            tasks: BTreeMap::new(),
+            task_queue: Arc::new(TaskQueue {
+                ids: ArrayQueue::new(TASK_QUEUE_SIZE),
+                overflowed: AtomicBool::new(false),
+            }),
+            waker_cache: BTreeMap::new(),
+            retired_wakers: Vec::new(),
+        }
+    }
+
+    // a new task gets polled once on the next run, after that only when woken
+    pub fn spawn(&mut self, task: Task){
+        let id = task.id;
+        if self.tasks.insert(id, task).is_some() {
+            panic!("task {:?} spawned twice", id);
+        }
+        self.task_queue.push(id);
+    }
+
+    // tasks that haven't finished yet
+    pub fn task_count(&self) -> usize {
+        self.tasks.len()
+    }
+
+    /**
+     * poll tasks forever, halting the cpu whenever none are ready
+     * for the end of kernel_main, in place of hlt_loop
+     */
+    pub fn run(&mut self) -> ! {
+        loop {
+            self.run_ready_tasks();
+            self.sleep_if_idle();
+        }
+    }
+
+    /**
+     * like run, but returns once every task has finished
+     * a task that's never woken again keeps it halting forever
+     */
+    pub fn run_until_complete(&mut self){
+        while !self.tasks.is_empty() {
+            self.run_ready_tasks();
+            if !self.tasks.is_empty() {
+                self.sleep_if_idle();
+            }
+        }
+    }
+
+    fn run_ready_tasks(&mut self){
+        loop {
+            if self.task_queue.overflowed.swap(false, Ordering::SeqCst) {
+                let ids: Vec<TaskId> = self.tasks.keys().copied().collect();
+                for id in ids {
+                    self.poll_task(id);
+                }
+            }
+            match self.task_queue.ids.pop() {
+                Some(id) => self.poll_task(id),
+                None => break,
+            }
+        }
+        // nobody else can reach a waker the executor holds the only reference to
+        self.retired_wakers.retain(|waker| Arc::strong_count(waker) > 1);
+    }
+
+    fn poll_task(&mut self, id: TaskId){
+        let task = match self.tasks.get_mut(&id) {
+            Some(task) => task,
+            // woken again after it finished
+            None => return,
+        };
+        let task_queue = &self.task_queue;
+        let task_waker = self.waker_cache
+            .entry(id)
+            .or_insert_with(|| Arc::new(TaskWaker { id, task_queue: task_queue.clone() }));
+        let waker = Waker::from(task_waker.clone());
+        let mut context = Context::from_waker(&waker);
+        if let Poll::Ready(()) = task.poll(&mut context) {
+            self.tasks.remove(&id);
+            if let Some(task_waker) = self.waker_cache.remove(&id) {
+                self.retired_wakers.push(task_waker);
+            }
+        }
+    }
+
+    /**
+     * hlt until the next interrupt if no task is ready
+     * checked with interrupts off, an interrupt waking a task between the check
+     * and hlt would otherwise leave it waiting until some other interrupt comes
+     */
+    fn sleep_if_idle(&self){
+        interrupts::disable();
+        if self.task_queue.is_empty() {
+            // sti only takes effect after the next instruction, so nothing gets in before hlt
+            interrupts::enable_and_hlt();
+        } else {
+            interrupts::enable();
+        }
+    }
+}
+
+struct TaskWaker{
+    id: TaskId,
+    task_queue: Arc<TaskQueue>,
+}
+
+impl TaskWaker{
+    fn wake_task(&self){
+        self.task_queue.push(self.id);
+    }
+}
+
+/**
+ * safe to call from interrupt handlers - no locks, never panics, and never frees
+ * the waker since the executor keeps a reference until nothing else has one
+ */
+impl Wake for TaskWaker{
+    fn wake(self: Arc<Self>){
+        self.wake_task();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>){
+        self.wake_task();
+    }
+}
+
+#[test_case]
+fn test_tasks_run_to_completion(){
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    static FINISHED: AtomicUsize = AtomicUsize::new(0);
+    async fn number() -> usize {
+        42
+    }
+    async fn add_number(){
+        assert_eq!(number().await, 42);
+        FINISHED.fetch_add(1, Ordering::SeqCst);
+    }
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(add_number()));
+    executor.spawn(Task::new(add_number()));
+    executor.run_until_complete();
+    assert_eq!(FINISHED.load(Ordering::SeqCst), 2);
+    assert_eq!(executor.task_count(), 0);
+}
+
+#[test_case]
+fn test_yielding_tasks_take_turns(){
+    use alloc::{rc::Rc, vec::Vec};
+    use core::cell::RefCell;
+    let order = Rc::new(RefCell::new(Vec::new()));
+    let mut executor = Executor::new();
+    for task in 0..2 {
+        let order = order.clone();
+        executor.spawn(Task::new(async move {
+            for step in 0..3 {
+                order.borrow_mut().push((task, step));
+                super::yield_now().await;
+            }
+        }));
+    }
+    executor.run_until_complete();
+    assert_eq!(*order.borrow(), [(0, 0), (1, 0), (0, 1), (1, 1), (0, 2), (1, 2)]);
+}
+
+#[test_case]
+fn test_wakes_past_a_full_queue_still_poll(){
+    use core::future::Future;
+    use core::pin::Pin;
+    use spin::Mutex;
+    static WAKER: Mutex<Option<Waker>> = Mutex::new(None);
+    struct PendingOnce{
+        polled: bool,
+    }
+    impl Future for PendingOnce{
+        type Output = ();
+        fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+            if self.polled {
+                return Poll::Ready(());
+            }
+            self.polled = true;
+            *WAKER.lock() = Some(context.waker().clone());
+            Poll::Pending
+        }
+    }
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(PendingOnce { polled: false }));
+    executor.run_ready_tasks();
+    let waker = WAKER.lock().take().expect("task wasn't polled");
+    for _ in 0..TASK_QUEUE_SIZE * 2 {
+        waker.wake_by_ref();
+    }
+    executor.run_until_complete();
+    assert_eq!(executor.task_count(), 0);
+    // the finished task's waker is still out there, so the executor keeps it alive
+    assert_eq!(executor.retired_wakers.len(), 1);
+    drop(waker);
+    executor.run_ready_tasks();
+    assert!(executor.retired_wakers.is_empty());
+}
+
+#[test_case]
+fn test_interrupt_wakes_a_sleeping_task(){
+    use core::future::Future;
+    use core::pin::Pin;
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use spin::Mutex;
+    // woken from a timer callback, which runs in the timer interrupt
+    static WAKER: Mutex<Option<Waker>> = Mutex::new(None);
+    static FIRED: AtomicBool = AtomicBool::new(false);
+    fn fire(){
+        FIRED.store(true, Ordering::SeqCst);
+        if let Some(waker) = WAKER.lock().take() {
+            waker.wake();
+        }
+    }
+    struct WaitForTimer;
+    impl Future for WaitForTimer{
+        type Output = ();
+        fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+            interrupts::without_interrupts(|| {
+                if FIRED.load(Ordering::SeqCst) {
+                    return Poll::Ready(());
+                }
+                *WAKER.lock() = Some(context.waker().clone());
+                Poll::Pending
+            })
+        }
+    }
+    crate::time::set_timeout(core::time::Duration::from_millis(3), fire).expect("timer heap full");
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(WaitForTimer));
+    executor.run_until_complete();
+    assert!(FIRED.load(Ordering::SeqCst));
+}
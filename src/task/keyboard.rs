@@ -0,0 +1,593 @@
+// keyboard input as an async stream
+// the interrupt handler only queues raw scancodes, decoding them into keys
+// happens in a task, where it can take as long as it likes and hold locks
+use alloc::boxed::Box;
+use conquer_once::spin::OnceCell;
+use core::pin::Pin;
+use core::str::FromStr;
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use core::task::{Context, Poll};
+use futures_util::stream::{Stream, StreamExt};
+use futures_util::task::AtomicWaker;
+use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, KeyboardLayout, ScancodeSet1, ScancodeSet2};
+use spin::Mutex;
+use crate::ps2::{Leds, Ps2Error};
+use super::byte_queue::ByteQueue;
+use crate::{print, println};
+
+// layouts pc_keyboard doesn't have
+mod de105;
+use de105::De105Key;
+
+// scancodes that can pile up before the task gets to them, a key press and release is 2-6
+const SCANCODE_QUEUE_SIZE: usize = 100;
+
+// made by ScancodeStream::new, interrupts before then have nowhere to put their scancodes
+static SCANCODE_QUEUE: OnceCell<ByteQueue> = OnceCell::uninit();
+// scancodes thrown away, because the queue was full or didn't exist yet
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/**
+ * called by the keyboard interrupt handler
+ * never blocks or allocates - a full queue drops the scancode and counts it instead
+ */
+pub(crate) fn add_scancode(scancode: u8){
+    let queued = match SCANCODE_QUEUE.try_get() {
+        Ok(queue) => queue.push(scancode),
+        Err(_) => false,
+    };
+    if !queued {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// scancodes lost since boot - see add_scancode
+pub fn dropped_scancodes() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/**
+ * the scancodes from the keyboard interrupt, in order
+ * only one can exist - each scancode goes to a single reader
+ */
+pub struct ScancodeStream{
+    _private: (),
+}
+
+impl ScancodeStream{
+    pub fn new() -> ScancodeStream {
+        SCANCODE_QUEUE
+            .try_init_once(|| ByteQueue::new(SCANCODE_QUEUE_SIZE))
+            .expect("ScancodeStream::new should only be called once");
+        ScancodeStream { _private: () }
+    }
+}
+
+impl Default for ScancodeStream{
+    fn default() -> ScancodeStream {
+        ScancodeStream::new()
+    }
+}
+
+// never ends, keeps waiting for the next key
+impl Stream for ScancodeStream{
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<u8>> {
+        let queue = SCANCODE_QUEUE.try_get().expect("scancode queue not initialized");
+        queue.poll_pop(context).map(Some)
+    }
+}
+
+// which key each key position types
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout{
+    Us104,
+    Uk105,
+    // qwertz, see de105
+    De105,
+    Dvorak104,
+}
+
+/**
+ * what the keyboard's bytes mean - set 1 is the original PC/XT one,
+ * set 2 what keyboards really send since the AT
+ * the 8042 controller translates set 2 into set 1 unless its translation is turned off
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScancodeSet{
+    Set1,
+    Set2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyboardConfig{
+    pub layout: Layout,
+    pub scancode_set: ScancodeSet,
+}
+
+impl Default for KeyboardConfig{
+    fn default() -> KeyboardConfig {
+        KeyboardConfig { layout: Layout::Us104, scancode_set: ScancodeSet::Set1 }
+    }
+}
+
+// the names a boot parameter or shell command would use, ex: "uk105".parse::<Layout>()
+impl FromStr for Layout{
+    type Err = ();
+
+    fn from_str(name: &str) -> Result<Layout, ()> {
+        match name {
+            "us" | "us104" => Ok(Layout::Us104),
+            "uk" | "uk105" => Ok(Layout::Uk105),
+            "de" | "de105" => Ok(Layout::De105),
+            "dvorak" | "dvorak104" => Ok(Layout::Dvorak104),
+            _ => Err(()),
+        }
+    }
+}
+
+// "1" or "2"
+impl FromStr for ScancodeSet{
+    type Err = ();
+
+    fn from_str(name: &str) -> Result<ScancodeSet, ()> {
+        match name {
+            "1" | "set1" => Ok(ScancodeSet::Set1),
+            "2" | "set2" => Ok(ScancodeSet::Set2),
+            _ => Err(()),
+        }
+    }
+}
+
+static CONFIG: Mutex<KeyboardConfig> = Mutex::new(KeyboardConfig { layout: Layout::Us104, scancode_set: ScancodeSet::Set1 });
+// bumped by every change, so the decoding task knows to pick it up
+static CONFIG_VERSION: AtomicU64 = AtomicU64::new(0);
+
+pub fn config() -> KeyboardConfig {
+    *CONFIG.lock()
+}
+
+/**
+ * switch layout and scancode set, starting with the next scancode
+ * a key held down while switching can come out wrong once
+ * a new scancode set needs the controller's translation switched to match - without
+ * a working controller the keyboard's bytes stay set 1, and set 2 is refused
+ */
+pub fn set_config(config: KeyboardConfig) -> Result<(), Ps2Error> {
+    let translate = config.scancode_set == ScancodeSet::Set1;
+    if config.scancode_set != self::config().scancode_set {
+        if crate::ps2::is_initialized() {
+            crate::ps2::set_translation(translate)?;
+        } else if !translate {
+            return Err(Ps2Error::NotInitialized);
+        }
+    }
+    *CONFIG.lock() = config;
+    CONFIG_VERSION.fetch_add(1, Ordering::SeqCst);
+    Ok(())
+}
+
+/**
+ * the config to boot with, set before ps2::init so the controller's translation
+ * is set up to match it from the start - init_with drops back to set 1 if the
+ * controller doesn't come up
+ */
+pub(crate) fn set_boot_config(config: KeyboardConfig){
+    *CONFIG.lock() = config;
+    CONFIG_VERSION.fetch_add(1, Ordering::SeqCst);
+}
+
+pub fn set_layout(layout: Layout) -> Result<(), Ps2Error> {
+    set_config(KeyboardConfig { layout, ..config() })
+}
+
+pub fn set_scancode_set(scancode_set: ScancodeSet) -> Result<(), Ps2Error> {
+    set_config(KeyboardConfig { scancode_set, ..config() })
+}
+
+pub use pc_keyboard::{KeyCode, KeyState};
+
+/**
+ * modifier keys held down, and lock keys toggled on
+ * left and right are kept apart, ex: right alt is AltGr on layouts that have it
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Modifiers{
+    pub left_shift: bool,
+    pub right_shift: bool,
+    pub left_ctrl: bool,
+    pub right_ctrl: bool,
+    pub alt: bool,
+    // right alt, for the third character on a key
+    pub alt_gr: bool,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+impl Modifiers{
+    pub fn shift(&self) -> bool {
+        self.left_shift || self.right_shift
+    }
+
+    pub fn ctrl(&self) -> bool {
+        self.left_ctrl || self.right_ctrl
+    }
+
+    /**
+     * follow one key going down or up, false if it isn't a modifier
+     * lock keys toggle on the press, repeat is true for the keyboard's typematic
+     * repeats of a key that's still held so they don't toggle again
+     */
+    fn update(&mut self, code: KeyCode, state: KeyState, repeat: bool) -> bool {
+        let down = state == KeyState::Down;
+        let toggle = down && !repeat;
+        match code {
+            KeyCode::ShiftLeft => self.left_shift = down,
+            KeyCode::ShiftRight => self.right_shift = down,
+            KeyCode::ControlLeft => self.left_ctrl = down,
+            KeyCode::ControlRight => self.right_ctrl = down,
+            KeyCode::AltLeft => self.alt = down,
+            KeyCode::AltRight => self.alt_gr = down,
+            KeyCode::CapsLock => self.caps_lock ^= toggle,
+            KeyCode::NumpadLock => self.num_lock ^= toggle,
+            KeyCode::ScrollLock => self.scroll_lock ^= toggle,
+            _ => return false,
+        }
+        true
+    }
+
+    // what pc_keyboard's layouts look at
+    fn for_layout(&self) -> pc_keyboard::Modifiers {
+        pc_keyboard::Modifiers {
+            lshift: self.left_shift,
+            rshift: self.right_shift,
+            lctrl: self.left_ctrl,
+            rctrl: self.right_ctrl,
+            numlock: self.num_lock,
+            capslock: self.caps_lock,
+            alt_gr: self.alt_gr,
+        }
+    }
+}
+
+// a key going down or up, ex: from KeyEventStream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent{
+    pub code: KeyCode,
+    pub state: KeyState,
+    // with this key already counted, ex: pressing left shift has left_shift set
+    pub modifiers: Modifiers,
+    /**
+     * what the key types with the current layout and modifiers, None on release and for
+     * keys that don't type anything - ctrl doesn't change it, see ctrl_combo
+     */
+    pub character: Option<char>,
+}
+
+impl KeyEvent{
+    pub fn is_press(&self) -> bool {
+        self.state == KeyState::Down
+    }
+
+    // the letter of a Ctrl combination, lowercase whatever shift says, ex: Some('c') for Ctrl+C
+    pub fn ctrl_combo(&self) -> Option<char> {
+        if !self.is_press() || !self.modifiers.ctrl() || self.modifiers.alt_gr {
+            return None;
+        }
+        self.character
+            .filter(char::is_ascii_alphabetic)
+            .map(|letter| letter.to_ascii_lowercase())
+    }
+}
+
+// as of the last key the decoding task saw, ex: for the keyboard LEDs
+static MODIFIERS: Mutex<Modifiers> = Mutex::new(Modifiers {
+    left_shift: false, right_shift: false, left_ctrl: false, right_ctrl: false,
+    alt: false, alt_gr: false, caps_lock: false, num_lock: false, scroll_lock: false,
+});
+
+pub fn modifiers() -> Modifiers {
+    *MODIFIERS.lock()
+}
+
+// pc_keyboard picks the layout and scancode set at compile time, this hides which ones
+trait Decoder{
+    // the key a scancode finished, if any - extended keys take a few bytes
+    fn add_byte(&mut self, scancode: u8) -> Option<(KeyCode, KeyState)>;
+    fn map_keycode(&self, code: KeyCode, modifiers: &pc_keyboard::Modifiers) -> DecodedKey;
+}
+
+impl<L: KeyboardLayout, S: pc_keyboard::ScancodeSet> Decoder for Keyboard<L, S>{
+    fn add_byte(&mut self, scancode: u8) -> Option<(KeyCode, KeyState)> {
+        match Keyboard::add_byte(self, scancode) {
+            Ok(Some(key_event)) => Some((key_event.code, key_event.state)),
+            _ => None,
+        }
+    }
+
+    // Keyboard keeps modifiers of its own, but doesn't know about alt or scroll lock
+    fn map_keycode(&self, code: KeyCode, modifiers: &pc_keyboard::Modifiers) -> DecodedKey {
+        // ctrl combinations are reported through the modifiers, not as control characters
+        L::map_keycode(code, modifiers, HandleControl::Ignore)
+    }
+}
+
+/**
+ * set 1 on an ISO keyboard - pc_keyboard 0.5 names its keys after a US keyboard
+ * the key left of enter (0x2b) is BackSlash there and the extra key right of left shift (0x56)
+ * has no name at all, so both get the names set 2 gives them instead
+ */
+struct IsoSet1<L: KeyboardLayout>{
+    keyboard: Keyboard<L, ScancodeSet1>,
+    // the last byte was 0xe0, the next one is an extended key
+    extended: bool,
+}
+
+impl<L: KeyboardLayout> IsoSet1<L>{
+    fn new(layout: L) -> IsoSet1<L> {
+        IsoSet1 { keyboard: Keyboard::new(layout, ScancodeSet1, HandleControl::Ignore), extended: false }
+    }
+}
+
+impl<L: KeyboardLayout> Decoder for IsoSet1<L>{
+    fn add_byte(&mut self, scancode: u8) -> Option<(KeyCode, KeyState)> {
+        let extended = core::mem::replace(&mut self.extended, scancode == 0xe0);
+        // the top bit is a release
+        let state = if scancode & 0x80 != 0 { KeyState::Up } else { KeyState::Down };
+        match scancode & 0x7f {
+            0x2b if !extended => Some((KeyCode::HashTilde, state)),
+            0x56 if !extended => Some((KeyCode::BackSlash, state)),
+            _ => Decoder::add_byte(&mut self.keyboard, scancode),
+        }
+    }
+
+    fn map_keycode(&self, code: KeyCode, modifiers: &pc_keyboard::Modifiers) -> DecodedKey {
+        self.keyboard.map_keycode(code, modifiers)
+    }
+}
+
+fn decoder(config: KeyboardConfig) -> Box<dyn Decoder> {
+    let control = HandleControl::Ignore;
+    match (config.layout, config.scancode_set) {
+        (Layout::Us104, ScancodeSet::Set1) => Box::new(Keyboard::new(layouts::Us104Key, ScancodeSet1, control)),
+        (Layout::Us104, ScancodeSet::Set2) => Box::new(Keyboard::new(layouts::Us104Key, ScancodeSet2, control)),
+        (Layout::Uk105, ScancodeSet::Set1) => Box::new(IsoSet1::new(layouts::Uk105Key)),
+        (Layout::Uk105, ScancodeSet::Set2) => Box::new(Keyboard::new(layouts::Uk105Key, ScancodeSet2, control)),
+        (Layout::De105, ScancodeSet::Set1) => Box::new(IsoSet1::new(De105Key)),
+        (Layout::De105, ScancodeSet::Set2) => Box::new(Keyboard::new(De105Key, ScancodeSet2, control)),
+        (Layout::Dvorak104, ScancodeSet::Set1) => Box::new(Keyboard::new(layouts::Dvorak104Key, ScancodeSet1, control)),
+        (Layout::Dvorak104, ScancodeSet::Set2) => Box::new(Keyboard::new(layouts::Dvorak104Key, ScancodeSet2, control)),
+    }
+}
+
+// scancodes in, key events out, following set_config
+struct KeyDecoder{
+    keyboard: Box<dyn Decoder>,
+    // CONFIG_VERSION keyboard was made for
+    version: u64,
+    modifiers: Modifiers,
+    // lock keys down right now, so repeats don't toggle them
+    locks_down: [bool; 3],
+}
+
+impl KeyDecoder{
+    fn new() -> KeyDecoder {
+        KeyDecoder::with_config(config())
+    }
+
+    // config until the next set_config
+    fn with_config(config: KeyboardConfig) -> KeyDecoder {
+        KeyDecoder {
+            keyboard: decoder(config),
+            version: CONFIG_VERSION.load(Ordering::SeqCst),
+            modifiers: Modifiers::default(),
+            locks_down: [false; 3],
+        }
+    }
+
+    fn add_byte(&mut self, scancode: u8) -> Option<KeyEvent> {
+        let version = CONFIG_VERSION.load(Ordering::SeqCst);
+        if version != self.version {
+            // modifiers carry over, only the meaning of the bytes and keys changes
+            self.version = version;
+            self.keyboard = decoder(config());
+        }
+        let (code, state) = self.keyboard.add_byte(scancode)?;
+        let lock = match code {
+            KeyCode::CapsLock => Some(0),
+            KeyCode::NumpadLock => Some(1),
+            KeyCode::ScrollLock => Some(2),
+            _ => None,
+        };
+        let mut repeat = false;
+        if let Some(lock) = lock {
+            repeat = self.locks_down[lock] && state == KeyState::Down;
+            self.locks_down[lock] = state == KeyState::Down;
+        }
+        let is_modifier = self.modifiers.update(code, state, repeat);
+        let character = match state {
+            KeyState::Down if !is_modifier => {
+                match self.keyboard.map_keycode(code, &self.modifiers.for_layout()) {
+                    DecodedKey::Unicode(character) => Some(character),
+                    DecodedKey::RawKey(_) => None,
+                }
+            }
+            _ => None,
+        };
+        Some(KeyEvent { code, state, modifiers: self.modifiers, character })
+    }
+}
+
+// the keyboard's lights show the lock keys
+fn leds(modifiers: &Modifiers) -> Leds {
+    Leds {
+        scroll_lock: modifiers.scroll_lock,
+        num_lock: modifiers.num_lock,
+        caps_lock: modifiers.caps_lock,
+    }
+}
+
+// lights waiting for update_leds, only the latest matters
+static PENDING_LEDS: AtomicU8 = AtomicU8::new(0);
+const LEDS_PENDING: u8 = 1 << 7;
+static LEDS_WAKER: AtomicWaker = AtomicWaker::new();
+
+fn request_leds(leds: Leds){
+    PENDING_LEDS.store(LEDS_PENDING | leds.bits(), Ordering::SeqCst);
+    LEDS_WAKER.wake();
+}
+
+/**
+ * keeps the keyboard's lights in step with the lock keys, ex: executor.spawn(Task::new(update_leds()))
+ * a task of its own, the keyboard takes a while to answer and key events shouldn't wait on it
+ */
+pub async fn update_leds(){
+    loop {
+        let bits = core::future::poll_fn(|context| {
+            LEDS_WAKER.register(context.waker());
+            match PENDING_LEDS.swap(0, Ordering::SeqCst) {
+                bits if bits & LEDS_PENDING != 0 => {
+                    LEDS_WAKER.take();
+                    Poll::Ready(bits)
+                }
+                _ => Poll::Pending,
+            }
+        })
+        .await;
+        // a keyboard that doesn't take it just keeps its lights as they were
+        let _ = crate::ps2::set_leds_async(Leds::from_bits(bits)).await;
+    }
+}
+
+/**
+ * key presses and releases from the keyboard interrupt
+ * made from the ScancodeStream, so only one can exist too
+ */
+pub struct KeyEventStream{
+    scancodes: ScancodeStream,
+    decoder: KeyDecoder,
+}
+
+impl KeyEventStream{
+    pub fn new() -> KeyEventStream {
+        KeyEventStream { scancodes: ScancodeStream::new(), decoder: KeyDecoder::new() }
+    }
+}
+
+impl Default for KeyEventStream{
+    fn default() -> KeyEventStream {
+        KeyEventStream::new()
+    }
+}
+
+impl Stream for KeyEventStream{
+    type Item = KeyEvent;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<KeyEvent>> {
+        let this = self.get_mut();
+        // a key can take several scancodes, keep going until one finishes or the queue is empty
+        loop {
+            match Pin::new(&mut this.scancodes).poll_next(context) {
+                Poll::Ready(Some(scancode)) => {
+                    if let Some(event) = this.decoder.add_byte(scancode) {
+                        let before = core::mem::replace(&mut *MODIFIERS.lock(), event.modifiers);
+                        if leds(&before) != leds(&event.modifiers) && crate::ps2::is_initialized() {
+                            request_leds(leds(&event.modifiers));
+                        }
+                        return Poll::Ready(Some(event));
+                    }
+                }
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+/**
+ * the console - print what keys type, ex: executor.spawn(Task::new(print_keypresses()))
+ * Ctrl+C prints ^C and Ctrl+L clears the screen
+ * also says when the queue overflowed and scancodes were lost
+ */
+pub async fn print_keypresses(){
+    let mut keys = KeyEventStream::new();
+    let mut dropped = dropped_scancodes();
+
+    while let Some(key) = keys.next().await {
+        if dropped_scancodes() != dropped {
+            println!("[keyboard: {} scancodes dropped]", dropped_scancodes() - dropped);
+            dropped = dropped_scancodes();
+        }
+        match key.ctrl_combo() {
+            Some('c') => println!("^C"),
+            Some('l') => crate::vga_buffer::clear_screen(),
+            Some(_) => {}
+            // if we have a readable character, print it
+            None => if let Some(character) = key.character {
+                print!("{}", character);
+            }
+        }
+    }
+}
+
+#[test_case]
+fn test_layouts_and_scancode_sets(){
+    fn decode(layout: Layout, scancode_set: ScancodeSet, scancodes: &[u8]) -> Option<char> {
+        let mut keyboard = KeyDecoder::with_config(KeyboardConfig { layout, scancode_set });
+        scancodes.iter().fold(None, |_, &scancode| keyboard.add_byte(scancode)).and_then(|key| key.character)
+    }
+    // the key right of T, pressed
+    assert_eq!(decode(Layout::Us104, ScancodeSet::Set1, &[0x15]), Some('y'));
+    assert_eq!(decode(Layout::De105, ScancodeSet::Set1, &[0x15]), Some('z'));
+    assert_eq!(decode(Layout::De105, ScancodeSet::Set1, &[0x2c]), Some('y'));
+    // the ISO keys left of enter and right of left shift, in both sets
+    assert_eq!(decode(Layout::De105, ScancodeSet::Set1, &[0x2b]), Some('#'));
+    assert_eq!(decode(Layout::De105, ScancodeSet::Set1, &[0x56]), Some('<'));
+    assert_eq!(decode(Layout::De105, ScancodeSet::Set2, &[0x5d]), Some('#'));
+    assert_eq!(decode(Layout::De105, ScancodeSet::Set2, &[0x61]), Some('<'));
+    assert_eq!(decode(Layout::Uk105, ScancodeSet::Set1, &[0x2b]), Some('#'));
+    // right of P, and shift + 2
+    assert_eq!(decode(Layout::De105, ScancodeSet::Set1, &[0x1a]), Some('ü'));
+    assert_eq!(decode(Layout::De105, ScancodeSet::Set1, &[0x2a, 0x03]), Some('"'));
+    // the key right of tab
+    assert_eq!(decode(Layout::Dvorak104, ScancodeSet::Set1, &[0x10]), Some('\''));
+    // A in set 2
+    assert_eq!(decode(Layout::Uk105, ScancodeSet::Set2, &[0x1c]), Some('a'));
+    assert_eq!("dvorak".parse(), Ok(Layout::Dvorak104));
+    assert_eq!("2".parse(), Ok(ScancodeSet::Set2));
+}
+
+#[test_case]
+fn test_set_config(){
+    let before = config();
+    assert_eq!(set_layout(Layout::De105), Ok(()));
+    assert_eq!(config(), KeyboardConfig { layout: Layout::De105, ..before });
+    assert_eq!(set_config(before), Ok(()));
+    assert_eq!(config(), before);
+}
+
+#[test_case]
+fn test_key_events_and_modifiers(){
+    let mut keyboard = KeyDecoder::with_config(KeyboardConfig::default());
+    let mut key = |scancode: u8| keyboard.add_byte(scancode).expect("scancode set 1 keys are one byte");
+    // shift + a
+    assert!(key(0x2a).modifiers.left_shift);
+    let a = key(0x1e);
+    assert_eq!((a.code, a.state, a.character), (KeyCode::A, KeyState::Down, Some('A')));
+    assert_eq!(key(0xaa).modifiers, Modifiers::default());
+    let release = key(0x9e);
+    assert_eq!((release.state, release.character), (KeyState::Up, None));
+    // ctrl + c
+    key(0x1d);
+    assert_eq!(key(0x2e).ctrl_combo(), Some('c'));
+    assert_eq!(key(0x9d).modifiers, Modifiers::default());
+    // caps lock toggles once however long it's held
+    key(0x3a);
+    key(0x3a);
+    assert!(key(0xba).modifiers.caps_lock);
+    assert_eq!(key(0x1e).character, Some('A'));
+    key(0x3a);
+    assert!(!key(0xba).modifiers.caps_lock);
+}
@@ -0,0 +1,70 @@
+// German QWERTZ on a 105 key ISO keyboard - pc-keyboard 0.5 doesn't have it
+// keycodes are named after the US key in the same place, anything German
+// doesn't change is left to Us104Key - the two ISO keys go by their set 2 names,
+// see keyboard::IsoSet1 for set 1
+use pc_keyboard::layouts::Us104Key;
+use pc_keyboard::{DecodedKey, HandleControl, KeyCode, KeyboardLayout, Modifiers};
+
+pub struct De105Key;
+
+impl KeyboardLayout for De105Key{
+    fn map_keycode(keycode: KeyCode, modifiers: &Modifiers, handle_ctrl: HandleControl) -> DecodedKey {
+        let shift = modifiers.lshift || modifiers.rshift;
+        // umlauts follow caps lock like letters, everything else only shift
+        let caps = shift ^ modifiers.capslock;
+        let key = |plain, shifted| DecodedKey::Unicode(if shift { shifted } else { plain });
+        let letter = |lower, upper| DecodedKey::Unicode(if caps { upper } else { lower });
+        if modifiers.alt_gr {
+            if let Some(character) = alt_gr(keycode) {
+                return DecodedKey::Unicode(character);
+            }
+        }
+        match keycode {
+            KeyCode::BackTick => key('^', '°'),
+            KeyCode::Key2 => key('2', '"'),
+            KeyCode::Key3 => key('3', '§'),
+            KeyCode::Key6 => key('6', '&'),
+            KeyCode::Key7 => key('7', '/'),
+            KeyCode::Key8 => key('8', '('),
+            KeyCode::Key9 => key('9', ')'),
+            KeyCode::Key0 => key('0', '='),
+            KeyCode::Minus => key('ß', '?'),
+            KeyCode::Equals => key('´', '`'),
+            KeyCode::BracketSquareLeft => letter('ü', 'Ü'),
+            KeyCode::BracketSquareRight => key('+', '*'),
+            KeyCode::SemiColon => letter('ö', 'Ö'),
+            KeyCode::Quote => letter('ä', 'Ä'),
+            // left of enter
+            KeyCode::HashTilde => key('#', '\''),
+            // the extra ISO key right of left shift
+            KeyCode::BackSlash => key('<', '>'),
+            KeyCode::Comma => key(',', ';'),
+            KeyCode::Fullstop => key('.', ':'),
+            KeyCode::Slash => key('-', '_'),
+            // z and y swap places
+            KeyCode::Y => Us104Key::map_keycode(KeyCode::Z, modifiers, handle_ctrl),
+            KeyCode::Z => Us104Key::map_keycode(KeyCode::Y, modifiers, handle_ctrl),
+            _ => Us104Key::map_keycode(keycode, modifiers, handle_ctrl),
+        }
+    }
+}
+
+// the third character on a key, typed with right alt
+fn alt_gr(keycode: KeyCode) -> Option<char> {
+    let character = match keycode {
+        KeyCode::Key2 => '²',
+        KeyCode::Key3 => '³',
+        KeyCode::Key7 => '{',
+        KeyCode::Key8 => '[',
+        KeyCode::Key9 => ']',
+        KeyCode::Key0 => '}',
+        KeyCode::Minus => '\\',
+        KeyCode::Q => '@',
+        KeyCode::E => '€',
+        KeyCode::M => 'µ',
+        KeyCode::BracketSquareRight => '~',
+        KeyCode::BackSlash => '|',
+        _ => return None,
+    };
+    Some(character)
+}
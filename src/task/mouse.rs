@@ -0,0 +1,350 @@
+// the PS/2 mouse on the controller's second port, as an async stream
+// like the keyboard, the irq 12 handler only queues bytes, and packets are
+// put together and decoded in a task
+use conquer_once::spin::OnceCell;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+use futures_util::stream::{Stream, StreamExt};
+use crate::interrupts::irq::{self, IrqError};
+use crate::ps2::{self, Ps2Error};
+use crate::println;
+use super::byte_queue::ByteQueue;
+
+const MOUSE_IRQ: u8 = 12;
+
+// mouse commands
+const SET_DEFAULTS: u8 = 0xf6;
+const SET_SAMPLE_RATE: u8 = 0xf3;
+const GET_ID: u8 = 0xf2;
+const ENABLE_REPORTING: u8 = 0xf4;
+const RESET: u8 = 0xff;
+const RESET_PASSED: u8 = 0xaa;
+// a plain mouse, and one with a scroll wheel
+const ID_STANDARD: u8 = 0x00;
+const ID_INTELLIMOUSE: u8 = 0x03;
+const DEFAULT_SAMPLE_RATE: u8 = 100;
+
+// first byte of a packet
+const LEFT_BUTTON: u8 = 1 << 0;
+const RIGHT_BUTTON: u8 = 1 << 1;
+const MIDDLE_BUTTON: u8 = 1 << 2;
+// always set, how a lost byte gets noticed
+const ALWAYS_ONE: u8 = 1 << 3;
+// the 9th bit of the movement, it's two's complement
+const X_SIGN: u8 = 1 << 4;
+const Y_SIGN: u8 = 1 << 5;
+const X_OVERFLOW: u8 = 1 << 6;
+const Y_OVERFLOW: u8 = 1 << 7;
+
+// bytes that can pile up before the task gets to them, 4 to a packet at most
+const BYTE_QUEUE_SIZE: usize = 128;
+
+static BYTE_QUEUE: OnceCell<ByteQueue> = OnceCell::uninit();
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+// 3, or 4 with the scroll wheel turned on
+static PACKET_SIZE: AtomicUsize = AtomicUsize::new(3);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseError{
+    // no second PS/2 port, or nothing plugged into it
+    NoMouse,
+    Ps2(Ps2Error),
+    Irq(IrqError),
+}
+
+impl From<Ps2Error> for MouseError{
+    fn from(err: Ps2Error) -> MouseError {
+        MouseError::Ps2(err)
+    }
+}
+
+impl From<IrqError> for MouseError{
+    fn from(err: IrqError) -> MouseError {
+        MouseError::Irq(err)
+    }
+}
+
+/**
+ * reset the mouse, turn on the scroll wheel if it has one and start it reporting on irq 12
+ * after ps2::init, and with interrupts still off - the mouse's answers are polled
+ */
+pub fn init() -> Result<(), MouseError> {
+    if !ps2::has_second_port() {
+        return Err(MouseError::NoMouse);
+    }
+    ps2::enable_second_port()?;
+    // back off if the mouse can't be set up, so it never sends anything nobody reads
+    if let Err(err) = set_up() {
+        let _ = ps2::disable_second_port();
+        return Err(err);
+    }
+    INITIALIZED.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+fn set_up() -> Result<(), MouseError> {
+    ps2::second_port_command(RESET).map_err(|_| MouseError::NoMouse)?;
+    if ps2::read_device_byte()? != RESET_PASSED {
+        return Err(MouseError::NoMouse);
+    }
+    // the id, always a plain mouse straight after reset
+    ps2::read_device_byte()?;
+    ps2::second_port_command(SET_DEFAULTS)?;
+
+    // the IntelliMouse knock - sample rates of 200, 100 and 80 in a row turn the wheel on
+    for rate in [200, 100, 80] {
+        set_sample_rate(rate)?;
+    }
+    ps2::second_port_command(GET_ID)?;
+    let packet_size = match ps2::read_device_byte()? {
+        ID_INTELLIMOUSE => 4,
+        ID_STANDARD => 3,
+        other => return Err(MouseError::Ps2(Ps2Error::Unexpected(other))),
+    };
+    PACKET_SIZE.store(packet_size, Ordering::SeqCst);
+    set_sample_rate(DEFAULT_SAMPLE_RATE)?;
+
+    let handler = irq::register_irq(MOUSE_IRQ, mouse_interrupt_handler)?;
+    let reporting = ps2::second_port_command(ENABLE_REPORTING)
+        .and_then(|()| ps2::set_second_port_irq(true));
+    if let Err(err) = reporting {
+        // no handler left on irq 12 for a mouse that isn't there
+        let _ = ps2::set_second_port_irq(false);
+        irq::unregister_irq(handler);
+        return Err(err.into());
+    }
+    Ok(())
+}
+
+fn set_sample_rate(rate: u8) -> Result<(), Ps2Error> {
+    ps2::second_port_command(SET_SAMPLE_RATE)?;
+    ps2::second_port_command(rate)
+}
+
+pub fn is_initialized() -> bool {
+    INITIALIZED.load(Ordering::SeqCst)
+}
+
+// whether init found a scroll wheel
+pub fn has_scroll_wheel() -> bool {
+    PACKET_SIZE.load(Ordering::SeqCst) == 4
+}
+
+// only queues the byte, MouseEventStream puts packets together outside the interrupt
+fn mouse_interrupt_handler(_irq: u8){
+    let byte = match ps2::read_data() {
+        Some(byte) => byte,
+        None => return,
+    };
+    // or an answer to a command the ps2 driver is waiting on
+    if ps2::take_response(ps2::Ps2Port::Second, byte) {
+        return;
+    }
+    add_byte(byte);
+}
+
+// never blocks or allocates - a full queue drops the byte and counts it instead
+fn add_byte(byte: u8){
+    let queued = match BYTE_QUEUE.try_get() {
+        Ok(queue) => queue.push(byte),
+        Err(_) => false,
+    };
+    if !queued {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// bytes lost since boot, the stream resynchronizes on the next packet
+pub fn dropped_bytes() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MouseButtons{
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+}
+
+impl MouseButtons{
+    fn from_bits(bits: u8) -> MouseButtons {
+        MouseButtons {
+            left: bits & LEFT_BUTTON != 0,
+            right: bits & RIGHT_BUTTON != 0,
+            middle: bits & MIDDLE_BUTTON != 0,
+        }
+    }
+}
+
+/**
+ * one packet - how far the mouse moved since the last one, and its buttons
+ * dy is positive going up, the other way around from screen rows
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MouseEvent{
+    pub dx: i16,
+    pub dy: i16,
+    // wheel clicks, positive scrolling down - always 0 without a scroll wheel
+    pub scroll: i8,
+    pub buttons: MouseButtons,
+    // buttons held now that weren't in the last packet
+    pub pressed: MouseButtons,
+    // buttons that were held in the last packet and aren't now
+    pub released: MouseButtons,
+}
+
+// bytes in, packets out
+struct PacketDecoder{
+    packet: [u8; 4],
+    len: usize,
+    packet_size: usize,
+    buttons: u8,
+}
+
+impl PacketDecoder{
+    fn new(packet_size: usize) -> PacketDecoder {
+        PacketDecoder { packet: [0; 4], len: 0, packet_size, buttons: 0 }
+    }
+
+    fn add_byte(&mut self, byte: u8) -> Option<MouseEvent> {
+        // a first byte without its always one bit means bytes got lost, wait for the next packet
+        if self.len == 0 && byte & ALWAYS_ONE == 0 {
+            return None;
+        }
+        self.packet[self.len] = byte;
+        self.len += 1;
+        if self.len < self.packet_size {
+            return None;
+        }
+        self.len = 0;
+        Some(self.decode())
+    }
+
+    // bytes from queue until a packet is complete
+    fn poll_event(&mut self, queue: &ByteQueue, context: &mut Context) -> Poll<MouseEvent> {
+        loop {
+            let byte = match queue.poll_pop(context) {
+                Poll::Ready(byte) => byte,
+                Poll::Pending => return Poll::Pending,
+            };
+            if let Some(event) = self.add_byte(byte) {
+                return Poll::Ready(event);
+            }
+        }
+    }
+
+    fn decode(&mut self) -> MouseEvent {
+        let [flags, x, y, extra] = self.packet;
+        // 9 bit two's complement, thrown away when it overflowed
+        let movement = |value: u8, sign: u8, overflow: u8| {
+            if flags & overflow != 0 {
+                0
+            } else {
+                value as i16 - if flags & sign != 0 { 0x100 } else { 0 }
+            }
+        };
+        // the low 4 bits of the 4th byte, sign extended
+        let scroll = if self.packet_size == 4 { ((extra << 4) as i8) >> 4 } else { 0 };
+        let buttons = flags & (LEFT_BUTTON | RIGHT_BUTTON | MIDDLE_BUTTON);
+        let pressed = buttons & !self.buttons;
+        let released = self.buttons & !buttons;
+        self.buttons = buttons;
+        MouseEvent {
+            dx: movement(x, X_SIGN, X_OVERFLOW),
+            dy: movement(y, Y_SIGN, Y_OVERFLOW),
+            scroll,
+            buttons: MouseButtons::from_bits(buttons),
+            pressed: MouseButtons::from_bits(pressed),
+            released: MouseButtons::from_bits(released),
+        }
+    }
+}
+
+/**
+ * movement and button events from the mouse, ex: while let Some(event) = mouse.next().await
+ * only one can exist - each packet goes to a single reader
+ */
+pub struct MouseEventStream{
+    decoder: PacketDecoder,
+}
+
+impl MouseEventStream{
+    pub fn new() -> MouseEventStream {
+        BYTE_QUEUE
+            .try_init_once(|| ByteQueue::new(BYTE_QUEUE_SIZE))
+            .expect("MouseEventStream::new should only be called once");
+        MouseEventStream { decoder: PacketDecoder::new(PACKET_SIZE.load(Ordering::SeqCst)) }
+    }
+}
+
+impl Default for MouseEventStream{
+    fn default() -> MouseEventStream {
+        MouseEventStream::new()
+    }
+}
+
+// never ends, keeps waiting for the mouse to move
+impl Stream for MouseEventStream{
+    type Item = MouseEvent;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<MouseEvent>> {
+        let queue = BYTE_QUEUE.try_get().expect("mouse byte queue not initialized");
+        self.get_mut().decoder.poll_event(queue, context).map(Some)
+    }
+}
+
+// print every click and scroll, ex: executor.spawn(Task::new(print_mouse_events()))
+pub async fn print_mouse_events(){
+    let mut mouse = MouseEventStream::new();
+    while let Some(event) = mouse.next().await {
+        if event.pressed != MouseButtons::default() || event.scroll != 0 {
+            println!("[mouse: {:?} scroll {}]", event.pressed, event.scroll);
+        }
+    }
+}
+
+#[test_case]
+fn test_mouse_is_found(){
+    // qemu's PS/2 mouse has a scroll wheel
+    assert!(is_initialized());
+    assert!(has_scroll_wheel());
+}
+
+#[test_case]
+fn test_packets_are_decoded(){
+    let mut decoder = PacketDecoder::new(4);
+    // left button, 5 right, 3 down, scrolled up one
+    let event = [0x09 | Y_SIGN, 5, 0xfd, 0x0f].iter().fold(None, |_, &byte| decoder.add_byte(byte));
+    let left = MouseButtons { left: true, ..MouseButtons::default() };
+    assert_eq!(event, Some(MouseEvent { dx: 5, dy: -3, scroll: -1, buttons: left, pressed: left, released: MouseButtons::default() }));
+    // a stray byte without bit 3 is skipped, then the left button goes up
+    let event = [0x00, 0x08, 0, 0, 0].iter().fold(None, |_, &byte| decoder.add_byte(byte));
+    assert_eq!(event.map(|event| event.released), Some(left));
+}
+
+#[test_case]
+fn test_packets_are_put_together_from_the_queue(){
+    use alloc::sync::Arc;
+    use super::{Executor, Task};
+    static DONE: AtomicBool = AtomicBool::new(false);
+    let queue = Arc::new(ByteQueue::new(BYTE_QUEUE_SIZE));
+    let mut executor = Executor::new();
+    let reader = queue.clone();
+    executor.spawn(Task::new(async move {
+        let mut decoder = PacketDecoder::new(3);
+        let event = core::future::poll_fn(|context| decoder.poll_event(&reader, context)).await;
+        assert_eq!((event.dx, event.dy), (1, 2));
+        DONE.store(true, Ordering::SeqCst);
+    }));
+    // as if the interrupt handler got them, one at a time
+    executor.spawn(Task::new(async move {
+        for byte in [0x08, 1, 2] {
+            assert!(queue.push(byte));
+            super::yield_now().await;
+        }
+    }));
+    executor.run_until_complete();
+    assert!(DONE.load(Ordering::SeqCst));
+}
@@ -0,0 +1,115 @@
+// Gregory Vincent
+// timekeeping - the PIT's irq 0 drives a monotonic tick counter since boot,
+// and the TSC gives nanosecond timestamps in between
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use crate::interrupts::irq;
+
+// the 8253/8254 programmable interval timer
+pub mod pit;
+// the cpu's time stamp counter
+pub mod tsc;
+// the CMOS clock, for the date
+pub mod rtc;
+// the high precision event timer, for one shot and periodic timer interrupts
+pub mod hpet;
+// callbacks some time from now, and sleep
+pub mod timer;
+
+pub use tsc::Instant;
+pub use timer::{cancel, set_interval, set_timeout, sleep};
+
+// ticks per second the kernel asks the PIT for in init
+pub const DEFAULT_TICK_HZ: u32 = 1000;
+
+// irq 0s since init
+static TICKS: AtomicU64 = AtomicU64::new(0);
+/**
+ * PIT input clock cycles since init - each tick adds the divisor it was counted with,
+ * so uptime stays right when the frequency changes, and never drifts from rounding
+ */
+static PIT_CYCLES: AtomicU64 = AtomicU64::new(0);
+
+/**
+ * calibrate the TSC, then program the PIT to DEFAULT_TICK_HZ and start counting ticks
+ * the wall clock starts from the RTC's date here too, and the HPET is started if there is one
+ * the interrupt controller has to be set up first - see interrupts::init_controller
+ */
+pub fn init(){
+    // before calibrating, so the TSC gets measured against the HPET instead of the PIT
+    if let Err(err) = hpet::init() {
+        crate::serial_println!("no HPET ({:?}), only the PIT", err);
+    }
+    tsc::calibrate();
+    pit::set_frequency(DEFAULT_TICK_HZ);
+    irq::register_irq(0, timer_tick).expect("timer irq registration failed");
+    rtc::init();
+}
+
+/**
+ * for the start of log lines - the date and time once the RTC has been read,
+ * the uptime before that
+ */
+pub struct Timestamp;
+
+impl core::fmt::Display for Timestamp{
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match rtc::now() {
+            Some(date_time) => write!(f, "{}", date_time),
+            None => {
+                let uptime = uptime();
+                write!(f, "{}.{:03}", uptime.as_secs(), uptime.subsec_millis())
+            }
+        }
+    }
+}
+
+fn timer_tick(_irq: u8){
+    PIT_CYCLES.fetch_add(pit::divisor() as u64, Ordering::SeqCst);
+    let now = TICKS.fetch_add(1, Ordering::SeqCst) + 1;
+    timer::run_expired(now);
+}
+
+// timer interrupts since init, only ever goes up
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::SeqCst)
+}
+
+// high resolution timestamp, ex: let start = now(); ...; start.elapsed()
+pub fn now() -> Instant {
+    Instant::now()
+}
+
+// time since init, with the resolution of one tick
+pub fn uptime() -> Duration {
+    let cycles = PIT_CYCLES.load(Ordering::SeqCst) as u128;
+    let nanos = cycles * 1_000_000_000 / pit::PIT_FREQUENCY_HZ as u128;
+    Duration::from_nanos(nanos as u64)
+}
+
+#[test_case]
+fn test_ticks_advance(){
+    let start = ticks();
+    x86_64::instructions::hlt();
+    assert!(ticks() > start);
+}
+
+#[test_case]
+fn test_uptime_follows_ticks(){
+    let start_ticks = ticks();
+    let start = uptime();
+    while ticks() < start_ticks + 10 {
+        x86_64::instructions::hlt();
+    }
+    let elapsed = uptime() - start;
+    // 10 ticks at ~1000 Hz, give or take the tick we started in
+    assert!(elapsed >= Duration::from_millis(9), "{:?}", elapsed);
+    assert!(elapsed <= Duration::from_millis(20), "{:?}", elapsed);
+}
+
+#[test_case]
+fn test_frequency_is_rounded_to_a_divisor(){
+    // 1193182 / 1000 = 1193, which is 1000.15 Hz
+    assert_eq!(pit::set_frequency(DEFAULT_TICK_HZ), 1000);
+    assert_eq!(pit::divisor(), 1193);
+}
@@ -0,0 +1,360 @@
+// the high precision event timer - a fast counter that never stops, plus a few
+// comparators that raise an interrupt when the counter reaches them
+// found through ACPI, its registers are memory mapped
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::acpi;
+use crate::interrupts::{apic, irq::{self, IrqError, IrqHandlerId, IRQ_LINES, ISA_IRQ_LINES}};
+use crate::memory;
+
+// register offsets
+const CAPABILITIES: u64 = 0x000;
+const CONFIG: u64 = 0x010;
+const MAIN_COUNTER: u64 = 0x0f0;
+// each timer has its own block of registers
+const TIMER_CONFIG: u64 = 0x100;
+const TIMER_COMPARATOR: u64 = 0x108;
+const TIMER_STRIDE: u64 = 0x20;
+const REGISTERS_SIZE: u64 = 0x400;
+
+// capabilities - bits 63:32 are the counter period in femtoseconds, bits 12:8 the number of timers - 1
+const COUNTER_64_BIT: u64 = 1 << 13;
+// the spec says the period is never more than 100 ns
+const MAX_PERIOD_FS: u64 = 100_000_000;
+// config
+const ENABLE: u64 = 1 << 0;
+// legacy replacement routes timers 0 and 1 to irq 0 and 8 instead of their own route
+const LEGACY_REPLACEMENT: u64 = 1 << 1;
+// timer config - bits 13:9 pick the I/O APIC input, bits 63:32 say which ones are allowed
+const TIMER_LEVEL_TRIGGERED: u64 = 1 << 1;
+const TIMER_INTERRUPT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAPABLE: u64 = 1 << 4;
+// the next comparator write sets the periodic timer's first deadline instead of its period
+const TIMER_VALUE_SET: u64 = 1 << 6;
+const TIMER_ROUTE_SHIFT: u64 = 9;
+const TIMER_ROUTE_MASK: u64 = 0x1f << TIMER_ROUTE_SHIFT;
+
+// the spec allows 32 timers per block
+const MAX_TIMERS: usize = 32;
+// ticks a one shot's deadline is at least in the future, closer than that it could be
+// passed before the comparator is written and would only fire after the counter wraps
+const MIN_DELAY_TICKS: u64 = 64;
+
+// where the registers are mapped, 0 until init
+static BASE: AtomicU64 = AtomicU64::new(0);
+// femtoseconds per counter tick
+static PERIOD_FS: AtomicU64 = AtomicU64::new(0);
+static TIMERS_PRESENT: AtomicU64 = AtomicU64::new(0);
+static MIN_TICK: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HpetError{
+    // no HPET table, or it has nonsense in it
+    NotPresent,
+    MapFailed,
+    // every timer is in use, or none of the free ones can be periodic
+    NoFreeTimer,
+    // none of the free timers can reach an I/O APIC input past the ISA irqs - ex: with the PICs
+    NoUsableRoute,
+    Irq(IrqError),
+}
+
+impl From<IrqError> for HpetError{
+    fn from(err: IrqError) -> HpetError {
+        HpetError::Irq(err)
+    }
+}
+
+// a running timer, for stop
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HpetTimer{
+    index: usize,
+    // a one shot frees its timer when it fires, this tells a later user of the same timer apart
+    id: u64,
+}
+
+#[derive(Clone, Copy)]
+struct ActiveTimer{
+    id: u64,
+    callback: fn(),
+    periodic: bool,
+    irq: u8,
+    handler: IrqHandlerId,
+}
+
+/**
+ * only locked with interrupts disabled, the timers' irq handler takes it too
+ */
+static TIMERS: Mutex<[Option<ActiveTimer>; MAX_TIMERS]> = Mutex::new([None; MAX_TIMERS]);
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+unsafe fn read(offset: u64) -> u64 {
+    ptr::read_volatile((BASE.load(Ordering::SeqCst) + offset) as *const u64)
+}
+
+unsafe fn write(offset: u64, value: u64){
+    ptr::write_volatile((BASE.load(Ordering::SeqCst) + offset) as *mut u64, value)
+}
+
+fn timer_register(index: usize, register: u64) -> u64 {
+    register + index as u64 * TIMER_STRIDE
+}
+
+/**
+ * map the HPET, turn every timer's interrupt off and start the main counter
+ * the timers route to I/O APIC inputs, so the APIC has to be set up first to use them,
+ * the counter works either way
+ */
+pub fn init() -> Result<(), HpetError> {
+    if is_enabled() {
+        return Ok(());
+    }
+    let info = acpi::hpet().ok_or(HpetError::NotPresent)?;
+    let base = unsafe { memory::mmio::map(info.address, REGISTERS_SIZE) }.map_err(|_| HpetError::MapFailed)?;
+    BASE.store(base.as_u64(), Ordering::SeqCst);
+    let capabilities = unsafe { read(CAPABILITIES) };
+    let period = capabilities >> 32;
+    if period == 0 || period > MAX_PERIOD_FS {
+        BASE.store(0, Ordering::SeqCst);
+        return Err(HpetError::NotPresent);
+    }
+    PERIOD_FS.store(period, Ordering::SeqCst);
+    let timers = ((capabilities >> 8) & 0x1f) + 1;
+    TIMERS_PRESENT.store(timers, Ordering::SeqCst);
+    MIN_TICK.store(info.min_tick as u64, Ordering::SeqCst);
+    unsafe {
+        write(CONFIG, read(CONFIG) & !(ENABLE | LEGACY_REPLACEMENT));
+        for index in 0..timers as usize {
+            let config = timer_register(index, TIMER_CONFIG);
+            write(config, read(config) & !(TIMER_INTERRUPT_ENABLE | TIMER_PERIODIC));
+        }
+        write(CONFIG, read(CONFIG) | ENABLE);
+    }
+    Ok(())
+}
+
+pub fn is_enabled() -> bool {
+    BASE.load(Ordering::SeqCst) != 0
+}
+
+// the main counter, it goes up by one every period_fs femtoseconds
+pub fn counter() -> u64 {
+    assert!(is_enabled(), "HPET not initialized");
+    unsafe { read(MAIN_COUNTER) }
+}
+
+// femtoseconds per counter tick, ex: 10_000_000 for a 100 MHz HPET
+pub fn period_fs() -> u64 {
+    PERIOD_FS.load(Ordering::SeqCst)
+}
+
+// counter ticks per second
+pub fn frequency() -> u64 {
+    1_000_000_000_000_000 / period_fs()
+}
+
+// whether the counter is 64 bits wide, a 32 bit one wraps after a few minutes
+pub fn is_64_bit() -> bool {
+    is_enabled() && unsafe { read(CAPABILITIES) } & COUNTER_64_BIT != 0
+}
+
+fn duration_to_ticks(duration: Duration) -> u64 {
+    (duration.as_nanos() * 1_000_000 / period_fs() as u128) as u64
+}
+
+/**
+ * call callback once, delay from now
+ * the callback runs in interrupt context - see irq::IrqHandler
+ */
+pub fn one_shot(delay: Duration, callback: fn()) -> Result<HpetTimer, HpetError> {
+    let ticks = duration_to_ticks(delay).max(MIN_DELAY_TICKS);
+    start(ticks, callback, false)
+}
+
+/**
+ * call callback every period until stop
+ * rounded up to the smallest period the HPET can keep up with
+ */
+pub fn periodic(period: Duration, callback: fn()) -> Result<HpetTimer, HpetError> {
+    let ticks = duration_to_ticks(period).max(MIN_TICK.load(Ordering::SeqCst)).max(1);
+    start(ticks, callback, true)
+}
+
+/**
+ * turn a timer off before it fires again
+ * false if it already went away, ex: a one shot that has fired
+ */
+pub fn stop(timer: HpetTimer) -> bool {
+    let active = without_interrupts(|| {
+        let mut timers = TIMERS.lock();
+        match timers[timer.index] {
+            Some(active) if active.id == timer.id => {
+                disable(timer.index);
+                timers[timer.index].take()
+            }
+            _ => None,
+        }
+    });
+    match active {
+        Some(active) => {
+            irq::unregister_irq(active.handler);
+            true
+        }
+        None => false,
+    }
+}
+
+fn disable(index: usize){
+    let config = timer_register(index, TIMER_CONFIG);
+    unsafe { write(config, read(config) & !(TIMER_INTERRUPT_ENABLE | TIMER_PERIODIC)) };
+}
+
+// the lowest I/O APIC input this timer can use that nothing else has
+fn pick_route(capabilities: u32, timers: &[Option<ActiveTimer>]) -> Option<u8> {
+    (ISA_IRQ_LINES..IRQ_LINES).find(|&irq| {
+        capabilities & (1 << irq) != 0
+            && apic::has_gsi(irq as u32)
+            && !timers.iter().flatten().any(|active| active.irq == irq)
+    })
+}
+
+fn start(ticks: u64, callback: fn(), periodic: bool) -> Result<HpetTimer, HpetError> {
+    if !is_enabled() {
+        return Err(HpetError::NotPresent);
+    }
+    // interrupts off the whole time, the irq handler looks at the same list
+    without_interrupts(|| {
+        let mut timers = TIMERS.lock();
+        let mut free_timer = false;
+        for index in 0..TIMERS_PRESENT.load(Ordering::SeqCst) as usize {
+            let config = unsafe { read(timer_register(index, TIMER_CONFIG)) };
+            if timers[index].is_some() || (periodic && config & TIMER_PERIODIC_CAPABLE == 0) {
+                continue;
+            }
+            free_timer = true;
+            if let Some(irq) = pick_route((config >> 32) as u32, &*timers) {
+                // registered before the timer is armed, so the first interrupt has somewhere to go
+                let handler = irq::register_irq(irq, timer_interrupt)?;
+                let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+                timers[index] = Some(ActiveTimer { id, callback, periodic, irq, handler });
+                arm(index, irq, ticks, periodic);
+                return Ok(HpetTimer { index, id });
+            }
+        }
+        Err(if free_timer { HpetError::NoUsableRoute } else { HpetError::NoFreeTimer })
+    })
+}
+
+fn arm(index: usize, irq: u8, ticks: u64, periodic: bool){
+    let config_register = timer_register(index, TIMER_CONFIG);
+    let comparator = timer_register(index, TIMER_COMPARATOR);
+    unsafe {
+        let mut config = read(config_register) & !(TIMER_ROUTE_MASK | TIMER_LEVEL_TRIGGERED | TIMER_PERIODIC);
+        config |= (irq as u64) << TIMER_ROUTE_SHIFT | TIMER_INTERRUPT_ENABLE;
+        if periodic {
+            // the first write is the first deadline, the second the period added after each one
+            write(config_register, config | TIMER_PERIODIC | TIMER_VALUE_SET);
+            write(comparator, read(MAIN_COUNTER) + ticks);
+            write(comparator, ticks);
+        } else {
+            write(config_register, config);
+            write(comparator, read(MAIN_COUNTER) + ticks);
+        }
+    }
+}
+
+// every timer has its own irq, so the irq says which one fired
+fn timer_interrupt(irq: u8){
+    let fired = {
+        let mut timers = TIMERS.lock();
+        let index = timers.iter().position(|timer| matches!(timer, Some(active) if active.irq == irq));
+        match index {
+            Some(index) => {
+                let active = timers[index].expect("just found it");
+                if !active.periodic {
+                    disable(index);
+                    timers[index] = None;
+                }
+                Some(active)
+            }
+            None => None,
+        }
+    };
+    // outside the lock, so the callback can start and stop timers
+    if let Some(active) = fired {
+        if !active.periodic {
+            irq::unregister_irq(active.handler);
+        }
+        (active.callback)();
+    }
+}
+
+#[test_case]
+fn test_counter_advances(){
+    assert!(is_enabled(), "no HPET");
+    let start = counter();
+    let frequency = frequency();
+    // 10 ms at the HPET's rate, checked against the TSC
+    // only loosely - a busy host can stall the guest for a while at any point
+    let started_at = super::now();
+    while counter() - start < frequency / 100 {
+        core::hint::spin_loop();
+    }
+    let elapsed = started_at.elapsed();
+    assert!(elapsed >= Duration::from_millis(5), "{:?}", elapsed);
+    assert!(elapsed <= Duration::from_millis(100), "{:?}", elapsed);
+}
+
+#[test_case]
+fn test_one_shot_fires_once(){
+    static FIRED: AtomicU64 = AtomicU64::new(0);
+    fn fire(){
+        FIRED.fetch_add(1, Ordering::SeqCst);
+    }
+    let timer = one_shot(Duration::from_millis(2), fire).expect("no HPET timer");
+    while FIRED.load(Ordering::SeqCst) == 0 {
+        x86_64::instructions::hlt();
+    }
+    // a few more ticks for a second interrupt that shouldn't come
+    for _ in 0..5 {
+        x86_64::instructions::hlt();
+    }
+    assert_eq!(FIRED.load(Ordering::SeqCst), 1);
+    assert!(!stop(timer));
+}
+
+#[test_case]
+fn test_periodic_fires_until_stopped(){
+    static FIRED: AtomicU64 = AtomicU64::new(0);
+    fn fire(){
+        FIRED.fetch_add(1, Ordering::SeqCst);
+    }
+    let timer = periodic(Duration::from_millis(1), fire).expect("no periodic HPET timer");
+    while FIRED.load(Ordering::SeqCst) < 5 {
+        x86_64::instructions::hlt();
+    }
+    assert!(stop(timer));
+    let fired = FIRED.load(Ordering::SeqCst);
+    for _ in 0..5 {
+        x86_64::instructions::hlt();
+    }
+    assert_eq!(FIRED.load(Ordering::SeqCst), fired);
+}
+
+#[test_case]
+fn test_stopped_one_shot_never_fires(){
+    static FIRED: AtomicU64 = AtomicU64::new(0);
+    fn fire(){
+        FIRED.fetch_add(1, Ordering::SeqCst);
+    }
+    let timer = one_shot(Duration::from_millis(5), fire).expect("no HPET timer");
+    assert!(stop(timer));
+    for _ in 0..10 {
+        x86_64::instructions::hlt();
+    }
+    assert_eq!(FIRED.load(Ordering::SeqCst), 0);
+}
@@ -0,0 +1,78 @@
+// the 8253/8254 programmable interval timer
+// channel 0 is wired to irq 0 and counts down from a divisor at PIT_FREQUENCY_HZ,
+// raising the irq every time it wraps
+use core::sync::atomic::{AtomicU32, Ordering};
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+// the PIT's input clock - a third of the old NTSC color burst frequency
+pub const PIT_FREQUENCY_HZ: u32 = 1_193_182;
+
+const CHANNEL_0: u16 = 0x40;
+const CHANNEL_2: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+// channel 0, low byte then high byte, mode 2 (rate generator), binary counting
+const CHANNEL_0_RATE_GENERATOR: u8 = 0b0011_0100;
+// channel 2, low byte then high byte, mode 0 (output goes high at terminal count), binary counting
+const CHANNEL_2_ONE_SHOT: u8 = 0b1011_0000;
+// bit 0 gates channel 2, bit 1 connects it to the pc speaker, bit 5 reads its output
+const SPEAKER_CONTROL: u16 = 0x61;
+const CHANNEL_2_GATE: u8 = 1 << 0;
+const SPEAKER_ENABLE: u8 = 1 << 1;
+const CHANNEL_2_OUTPUT: u8 = 1 << 5;
+
+// a divisor of 0 means 65536, which is what the BIOS leaves behind - about 18.2 Hz
+const MAX_DIVISOR: u32 = 65536;
+static DIVISOR: AtomicU32 = AtomicU32::new(MAX_DIVISOR);
+// the ports need two writes in a row, nothing else may get in between
+static PORTS: Mutex<()> = Mutex::new(());
+
+/**
+ * make irq 0 fire hz times a second, or as close as the divisor allows
+ * returns the frequency the PIT really runs at
+ */
+pub fn set_frequency(hz: u32) -> u32 {
+    let divisor = (PIT_FREQUENCY_HZ / hz.max(1)).clamp(1, MAX_DIVISOR);
+    let _ports = PORTS.lock();
+    unsafe {
+        Port::new(COMMAND).write(CHANNEL_0_RATE_GENERATOR);
+        let mut channel_0 = Port::new(CHANNEL_0);
+        // 65536 doesn't fit, it's written as 0
+        channel_0.write((divisor & 0xff) as u8);
+        channel_0.write(((divisor >> 8) & 0xff) as u8);
+    }
+    DIVISOR.store(divisor, Ordering::SeqCst);
+    frequency()
+}
+
+// how many input clock cycles pass between two irqs
+pub fn divisor() -> u32 {
+    DIVISOR.load(Ordering::SeqCst)
+}
+
+pub fn frequency() -> u32 {
+    PIT_FREQUENCY_HZ / divisor()
+}
+
+/**
+ * spin until channel 2 has counted down cycles PIT clock cycles
+ * channel 2 isn't wired to an irq, so this works with interrupts off and doesn't
+ * disturb channel 0's ticks - ex: for measuring how fast another clock runs
+ */
+pub fn wait_cycles(cycles: u16){
+    let _ports = PORTS.lock();
+    unsafe {
+        let mut control: Port<u8> = Port::new(SPEAKER_CONTROL);
+        let previous = control.read();
+        // gate on, but keep the speaker quiet
+        control.write((previous & !SPEAKER_ENABLE) | CHANNEL_2_GATE);
+        Port::new(COMMAND).write(CHANNEL_2_ONE_SHOT);
+        let mut channel_2 = Port::new(CHANNEL_2);
+        channel_2.write((cycles & 0xff) as u8);
+        channel_2.write((cycles >> 8) as u8);
+        while control.read() & CHANNEL_2_OUTPUT == 0 {
+            core::hint::spin_loop();
+        }
+        control.write(previous);
+    }
+}
@@ -0,0 +1,264 @@
+// the CMOS real time clock - battery backed, keeps the date while the machine is off
+// its registers sit behind an index port and a data port
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+use crate::interrupts::irq::{self, IrqError, IrqHandlerId};
+
+const CMOS_INDEX: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+// not standard, but where every PC since the AT keeps it
+const CENTURY: u8 = 0x32;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+const STATUS_C: u8 = 0x0c;
+
+// status A - the clock is halfway through updating, values read now can be torn
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+// status B
+const PERIODIC_INTERRUPT_ENABLE: u8 = 1 << 6;
+const BINARY_MODE: u8 = 1 << 2;
+const HOURS_24: u8 = 1 << 1;
+// in 12 hour mode, the top bit of the hour means pm
+const HOUR_PM: u8 = 1 << 7;
+
+const RTC_IRQ: u8 = 8;
+
+// selecting a register and reading it are two port accesses, nothing may get in between
+static CMOS: Mutex<()> = Mutex::new(());
+
+fn read_register(register: u8) -> u8 {
+    without_interrupts(|| {
+        let _cmos = CMOS.lock();
+        unsafe {
+            Port::new(CMOS_INDEX).write(register);
+            Port::new(CMOS_DATA).read()
+        }
+    })
+}
+
+fn write_register(register: u8, value: u8){
+    without_interrupts(|| {
+        let _cmos = CMOS.lock();
+        unsafe {
+            Port::new(CMOS_INDEX).write(register);
+            Port::new(CMOS_DATA).write(value);
+        }
+    })
+}
+
+// a date and time of day, in whatever timezone the CMOS clock was set to - usually UTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime{
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl fmt::Display for DateTime{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+impl DateTime{
+    // days since 1970-01-01, from Howard Hinnant's days_from_civil
+    fn days_since_epoch(&self) -> i64 {
+        let year = self.year as i64 - if self.month <= 2 { 1 } else { 0 };
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let month = self.month as i64;
+        let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        era * 146097 + day_of_era - 719468
+    }
+
+    // seconds since 1970-01-01 00:00:00
+    pub fn unix_timestamp(&self) -> u64 {
+        let days = self.days_since_epoch() as u64;
+        days * 86400 + self.hour as u64 * 3600 + self.minute as u64 * 60 + self.second as u64
+    }
+
+    // the reverse of unix_timestamp, from Howard Hinnant's civil_from_days
+    pub fn from_unix_timestamp(timestamp: u64) -> DateTime {
+        let days = (timestamp / 86400) as i64 + 719468;
+        let seconds = timestamp % 86400;
+        let era = days.div_euclid(146097);
+        let day_of_era = days - era * 146097;
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_index = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+        let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (seconds / 3600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+// every register the date needs, exactly as the clock stores them
+#[derive(PartialEq, Eq)]
+struct RawTime([u8; 7]);
+
+fn read_raw() -> RawTime {
+    // wait for the update to finish, then we have almost a second to read everything
+    while read_register(STATUS_A) & UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+    RawTime([SECONDS, MINUTES, HOURS, DAY, MONTH, YEAR, CENTURY].map(read_register))
+}
+
+/**
+ * the date and time straight from the clock
+ * reads until two reads in a row agree, in case an update started part way through
+ * slow - can wait up to a second for an update to finish
+ */
+pub fn read() -> DateTime {
+    let mut raw = read_raw();
+    loop {
+        let again = read_raw();
+        if again == raw {
+            break;
+        }
+        raw = again;
+    }
+    let [second, minute, hour, day, month, year, century] = raw.0;
+    let status_b = read_register(STATUS_B);
+    let pm = hour & HOUR_PM != 0;
+    let hour = hour & !HOUR_PM;
+    // the clock can store numbers in binary or BCD, the firmware picks
+    let decode = |value: u8| if status_b & BINARY_MODE != 0 { value } else { from_bcd(value) };
+    let mut hour = decode(hour);
+    if status_b & HOURS_24 == 0 {
+        // 12 am is midnight, 12 pm is noon
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+    let century = match decode(century) {
+        // no century register, assume this one
+        0 => 20,
+        century => century,
+    };
+    DateTime {
+        year: century as u16 * 100 + decode(year) as u16,
+        month: decode(month),
+        day: decode(day),
+        hour,
+        minute: decode(minute),
+        second: decode(second),
+    }
+}
+
+// unix timestamp of the moment uptime was zero, set by init
+static BOOT_TIMESTAMP: AtomicU64 = AtomicU64::new(0);
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/**
+ * read the clock once, so now() can work it out from the uptime instead of
+ * going back to the slow CMOS every time
+ */
+pub fn init(){
+    let timestamp = read().unix_timestamp();
+    BOOT_TIMESTAMP.store(timestamp.saturating_sub(super::uptime().as_secs()), Ordering::SeqCst);
+    INITIALIZED.store(true, Ordering::SeqCst);
+}
+
+// wall clock time, None before init
+pub fn now() -> Option<DateTime> {
+    if !INITIALIZED.load(Ordering::SeqCst) {
+        return None;
+    }
+    Some(DateTime::from_unix_timestamp(BOOT_TIMESTAMP.load(Ordering::SeqCst) + super::uptime().as_secs()))
+}
+
+static PERIODIC_INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+static PERIODIC_HANDLER: Mutex<Option<IrqHandlerId>> = Mutex::new(None);
+
+// irq 8s since enable_periodic_interrupt
+pub fn periodic_interrupts() -> u64 {
+    PERIODIC_INTERRUPTS.load(Ordering::SeqCst)
+}
+
+/**
+ * fire irq 8 at 32768 >> (rate - 1) Hz, rate is 3 (8 kHz) to 15 (2 Hz)
+ * a cheap second timer - ex: to check the PIT against
+ */
+pub fn enable_periodic_interrupt(rate: u8) -> Result<(), IrqError> {
+    assert!((3..=15).contains(&rate), "RTC rate {} out of range", rate);
+    let mut handler = PERIODIC_HANDLER.lock();
+    if handler.is_none() {
+        *handler = Some(irq::register_irq(RTC_IRQ, periodic_interrupt)?);
+    }
+    // the low 4 bits of status A pick the rate
+    write_register(STATUS_A, (read_register(STATUS_A) & 0xf0) | rate);
+    write_register(STATUS_B, read_register(STATUS_B) | PERIODIC_INTERRUPT_ENABLE);
+    // a pending flag from before would keep the irq from ever firing
+    read_register(STATUS_C);
+    Ok(())
+}
+
+pub fn disable_periodic_interrupt(){
+    write_register(STATUS_B, read_register(STATUS_B) & !PERIODIC_INTERRUPT_ENABLE);
+    if let Some(handler) = PERIODIC_HANDLER.lock().take() {
+        irq::unregister_irq(handler);
+    }
+}
+
+fn periodic_interrupt(_irq: u8){
+    // reading status C acknowledges the interrupt, the clock won't raise another until then
+    read_register(STATUS_C);
+    PERIODIC_INTERRUPTS.fetch_add(1, Ordering::SeqCst);
+}
+
+#[test_case]
+fn test_unix_timestamp_round_trip(){
+    let date = DateTime { year: 2024, month: 2, day: 29, hour: 13, minute: 37, second: 42 };
+    assert_eq!(date.unix_timestamp(), 1_709_213_862);
+    assert_eq!(DateTime::from_unix_timestamp(date.unix_timestamp()), date);
+    let epoch = DateTime { year: 1970, month: 1, day: 1, hour: 0, minute: 0, second: 0 };
+    assert_eq!(DateTime::from_unix_timestamp(0), epoch);
+}
+
+#[test_case]
+fn test_read_gives_a_sane_date(){
+    let date = read();
+    assert!(date.year >= 2020, "{}", date);
+    assert!((1..=12).contains(&date.month) && (1..=31).contains(&date.day), "{}", date);
+    assert!(date.hour < 24 && date.minute < 60 && date.second < 60, "{}", date);
+}
+
+#[test_case]
+fn test_periodic_interrupt_fires(){
+    let before = periodic_interrupts();
+    // 1024 Hz
+    enable_periodic_interrupt(6).expect("irq 8 registration failed");
+    while periodic_interrupts() < before + 3 {
+        x86_64::instructions::hlt();
+    }
+    disable_periodic_interrupt();
+}
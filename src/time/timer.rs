@@ -0,0 +1,296 @@
+// kernel timers - run a function once some time from now, or over and over
+// pending timers sit in a min-heap ordered by deadline, and every PIT tick
+// runs the ones that are due
+// the heap lives in a fixed array, callbacks get queued from interrupt handlers
+// and the kernel heap's lock can't be taken there
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts::{self, without_interrupts};
+use super::pit;
+
+// how many timers can be pending at once
+pub const MAX_TIMERS: usize = 64;
+
+/**
+ * runs in interrupt context from the timer irq - keep it short
+ * it can set and cancel timers, but must not sleep
+ */
+pub type TimerCallback = fn();
+
+// what cancel needs to find a timer again, ids are never reused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId(u64);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerError{
+    // MAX_TIMERS timers are already pending
+    Full,
+}
+
+#[derive(Clone, Copy)]
+struct Timer{
+    // the tick it's due at
+    deadline: u64,
+    id: TimerId,
+    // ticks between runs, 0 for a one shot
+    period: u64,
+    callback: TimerCallback,
+}
+
+impl Timer{
+    // earlier deadlines first, ties in the order they were set
+    fn before(&self, other: &Timer) -> bool {
+        (self.deadline, self.id.0) < (other.deadline, other.id.0)
+    }
+}
+
+// a binary min-heap - the children of timers[i] are timers[2i + 1] and timers[2i + 2]
+struct TimerHeap{
+    timers: [Option<Timer>; MAX_TIMERS],
+    len: usize,
+}
+
+impl TimerHeap{
+    const fn new() -> TimerHeap {
+        TimerHeap { timers: [None; MAX_TIMERS], len: 0 }
+    }
+
+    fn get(&self, index: usize) -> &Timer {
+        self.timers[index].as_ref().expect("hole in the timer heap")
+    }
+
+    fn push(&mut self, timer: Timer) -> Result<(), TimerError> {
+        if self.len == MAX_TIMERS {
+            return Err(TimerError::Full);
+        }
+        self.timers[self.len] = Some(timer);
+        self.len += 1;
+        self.sift_up(self.len - 1);
+        Ok(())
+    }
+
+    fn peek(&self) -> Option<&Timer> {
+        self.timers[0].as_ref()
+    }
+
+    fn remove(&mut self, index: usize) -> Timer {
+        self.len -= 1;
+        self.timers.swap(index, self.len);
+        let removed = self.timers[self.len].take().expect("hole in the timer heap");
+        if index < self.len {
+            // the timer moved into the hole can belong either above or below it
+            self.sift_up(index);
+            self.sift_down(index);
+        }
+        removed
+    }
+
+    fn position(&self, id: TimerId) -> Option<usize> {
+        self.timers[..self.len].iter().position(|timer| matches!(timer, Some(timer) if timer.id == id))
+    }
+
+    fn sift_up(&mut self, mut index: usize){
+        while index > 0 {
+            let parent = (index - 1) / 2;
+            if !self.get(index).before(self.get(parent)) {
+                break;
+            }
+            self.timers.swap(index, parent);
+            index = parent;
+        }
+    }
+
+    fn sift_down(&mut self, mut index: usize){
+        loop {
+            let mut smallest = index;
+            for child in [2 * index + 1, 2 * index + 2] {
+                if child < self.len && self.get(child).before(self.get(smallest)) {
+                    smallest = child;
+                }
+            }
+            if smallest == index {
+                break;
+            }
+            self.timers.swap(index, smallest);
+            index = smallest;
+        }
+    }
+}
+
+/**
+ * only locked with interrupts disabled, otherwise a tick arriving while
+ * set_timeout holds the lock would spin forever in run_expired
+ */
+static TIMERS: Mutex<TimerHeap> = Mutex::new(TimerHeap::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/**
+ * whole ticks covering duration, rounded up, plus one since the current tick is
+ * already partly over - a timer never fires early, up to a tick late
+ */
+fn duration_to_ticks(duration: Duration) -> u64 {
+    let hz = pit::frequency() as u128;
+    let ticks = (duration.as_nanos() * hz).div_ceil(1_000_000_000);
+    ticks as u64 + 1
+}
+
+fn add(delay: u64, period: u64, callback: TimerCallback) -> Result<TimerId, TimerError> {
+    let id = TimerId(NEXT_ID.fetch_add(1, Ordering::SeqCst));
+    without_interrupts(|| {
+        let deadline = super::ticks() + delay;
+        TIMERS.lock().push(Timer { deadline, id, period, callback })
+    })?;
+    Ok(id)
+}
+
+// call callback once, duration from now
+pub fn set_timeout(duration: Duration, callback: TimerCallback) -> Result<TimerId, TimerError> {
+    add(duration_to_ticks(duration), 0, callback)
+}
+
+/**
+ * call callback every period until cancelled, starting one period from now
+ * deadlines are counted from the first one, so a late run doesn't push the rest back
+ * periods shorter than a tick run once a tick
+ */
+pub fn set_interval(period: Duration, callback: TimerCallback) -> Result<TimerId, TimerError> {
+    let ticks = (duration_to_ticks(period) - 1).max(1);
+    add(ticks + 1, ticks, callback)
+}
+
+/**
+ * stop a timer from running again
+ * false if it isn't pending, ex: a timeout that has already fired
+ */
+pub fn cancel(id: TimerId) -> bool {
+    without_interrupts(|| {
+        let mut timers = TIMERS.lock();
+        match timers.position(id) {
+            Some(index) => {
+                timers.remove(index);
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+// timers waiting to fire
+pub fn pending() -> usize {
+    without_interrupts(|| TIMERS.lock().len)
+}
+
+/**
+ * run every timer due at tick now, called from the timer irq
+ * the lock is dropped around each callback, so callbacks can set and cancel timers
+ */
+pub(super) fn run_expired(now: u64){
+    loop {
+        let due = {
+            let mut timers = TIMERS.lock();
+            match timers.peek() {
+                Some(timer) if timer.deadline <= now => {
+                    let mut timer = timers.remove(0);
+                    if timer.period != 0 {
+                        timer.deadline += timer.period;
+                        // just removed one, there's room
+                        let _ = timers.push(timer);
+                    }
+                    timer.callback
+                }
+                _ => break,
+            }
+        };
+        due();
+    }
+}
+
+/**
+ * wait at least duration, halting the cpu in between ticks instead of spinning
+ * interrupts have to be enabled, nothing would wake it up otherwise
+ */
+pub fn sleep(duration: Duration){
+    assert!(interrupts::are_enabled(), "sleep with interrupts disabled would never wake up");
+    let deadline = super::ticks() + duration_to_ticks(duration);
+    loop {
+        // checked with interrupts off, so the last tick can't arrive between the check and hlt
+        interrupts::disable();
+        if super::ticks() >= deadline {
+            interrupts::enable();
+            break;
+        }
+        interrupts::enable_and_hlt();
+    }
+}
+
+#[test_case]
+fn test_timeout_fires_once_after_the_delay(){
+    static FIRED_AT: AtomicU64 = AtomicU64::new(0);
+    static FIRED: AtomicU64 = AtomicU64::new(0);
+    fn fire(){
+        FIRED_AT.store(super::ticks(), Ordering::SeqCst);
+        FIRED.fetch_add(1, Ordering::SeqCst);
+    }
+    let start = super::ticks();
+    let id = set_timeout(Duration::from_millis(5), fire).expect("timer heap full");
+    while FIRED.load(Ordering::SeqCst) == 0 {
+        x86_64::instructions::hlt();
+    }
+    assert!(FIRED_AT.load(Ordering::SeqCst) >= start + 5);
+    sleep(Duration::from_millis(3));
+    assert_eq!(FIRED.load(Ordering::SeqCst), 1);
+    assert!(!cancel(id));
+}
+
+#[test_case]
+fn test_cancelled_timeout_never_fires(){
+    static FIRED: AtomicU64 = AtomicU64::new(0);
+    fn fire(){
+        FIRED.fetch_add(1, Ordering::SeqCst);
+    }
+    let id = set_timeout(Duration::from_millis(2), fire).expect("timer heap full");
+    assert!(cancel(id));
+    sleep(Duration::from_millis(5));
+    assert_eq!(FIRED.load(Ordering::SeqCst), 0);
+}
+
+#[test_case]
+fn test_interval_repeats_until_cancelled(){
+    static FIRED: AtomicU64 = AtomicU64::new(0);
+    fn fire(){
+        FIRED.fetch_add(1, Ordering::SeqCst);
+    }
+    let id = set_interval(Duration::from_millis(2), fire).expect("timer heap full");
+    sleep(Duration::from_millis(11));
+    assert!(cancel(id));
+    let fired = FIRED.load(Ordering::SeqCst);
+    // 11 ms at one run every 2 ms, give or take the partial tick at the start
+    assert!((4..=6).contains(&fired), "{}", fired);
+    sleep(Duration::from_millis(5));
+    assert_eq!(FIRED.load(Ordering::SeqCst), fired);
+}
+
+#[test_case]
+fn test_timers_fire_in_deadline_order(){
+    static ORDER: Mutex<[u8; 3]> = Mutex::new([0; 3]);
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    fn record(which: u8){
+        ORDER.lock()[NEXT.fetch_add(1, Ordering::SeqCst) as usize] = which;
+    }
+    set_timeout(Duration::from_millis(6), || record(3)).expect("timer heap full");
+    set_timeout(Duration::from_millis(2), || record(1)).expect("timer heap full");
+    set_timeout(Duration::from_millis(4), || record(2)).expect("timer heap full");
+    sleep(Duration::from_millis(8));
+    assert_eq!(*ORDER.lock(), [1, 2, 3]);
+}
+
+#[test_case]
+fn test_sleep_waits_at_least_the_duration(){
+    let start = super::now();
+    sleep(Duration::from_millis(10));
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(10), "{:?}", elapsed);
+    // only loosely bounded above, a busy host can stall the guest
+    assert!(elapsed <= Duration::from_millis(100), "{:?}", elapsed);
+}
@@ -0,0 +1,200 @@
+// the time stamp counter - counts cpu clock cycles, read with a single instruction
+// much finer than the PIT's ticks, but its rate has to be measured against a known clock first
+use core::ops::{Add, Sub};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::instructions::interrupts::without_interrupts;
+use super::{hpet, pit};
+
+// TSC cycles per second, 0 until calibrate has run
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+static INVARIANT: AtomicBool = AtomicBool::new(false);
+static CALIBRATED_WITH_HPET: AtomicBool = AtomicBool::new(false);
+
+// how long each calibration run waits on the reference clock
+const CALIBRATION_MS: u32 = 10;
+const CALIBRATION_RUNS: usize = 3;
+
+// _rdtsc and __cpuid are only unsafe on older toolchains
+#[allow(unused_unsafe)]
+pub fn read() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/**
+ * cpuid leaf 0x8000_0007, edx bit 8
+ * an invariant TSC runs at the same rate whatever the cpu's power state or frequency,
+ * without it the calibrated rate can be off after the cpu changes speed
+ */
+#[allow(unused_unsafe)]
+pub fn detect_invariant() -> bool {
+    use core::arch::x86_64::__cpuid;
+    let max_extended_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+    if max_extended_leaf < 0x8000_0007 {
+        return false;
+    }
+    unsafe { __cpuid(0x8000_0007) }.edx & (1 << 8) != 0
+}
+
+// which clock calibrate measured against
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reference{
+    Pit,
+    Hpet,
+}
+
+/**
+ * measure the TSC's rate against the HPET's counter, or the PIT's channel 2 without one
+ * the HPET has to be initialized first to be used - see hpet::init
+ * returns TSC cycles per second
+ */
+pub fn calibrate() -> u64 {
+    let with_hpet = hpet::is_enabled();
+    let hz = if with_hpet { calibrate_with_hpet() } else { calibrate_with_pit() };
+    TSC_HZ.store(hz, Ordering::SeqCst);
+    CALIBRATED_WITH_HPET.store(with_hpet, Ordering::SeqCst);
+    INVARIANT.store(detect_invariant(), Ordering::SeqCst);
+    hz
+}
+
+// takes the fastest of a few runs - anything interrupting a run only makes it look slower
+fn calibrate_with_pit() -> u64 {
+    let pit_cycles = (pit::PIT_FREQUENCY_HZ * CALIBRATION_MS / 1000) as u16;
+    let fewest_cycles = (0..CALIBRATION_RUNS)
+        .map(|_| {
+            without_interrupts(|| {
+                let start = read();
+                pit::wait_cycles(pit_cycles);
+                read() - start
+            })
+        })
+        .min()
+        .unwrap_or(0);
+    (fewest_cycles as u128 * pit::PIT_FREQUENCY_HZ as u128 / pit_cycles as u128) as u64
+}
+
+/**
+ * both counters are read at the start and end of each run, so only something landing
+ * between the two reads throws a run off - which makes the TSC look faster, keep the slowest
+ */
+fn calibrate_with_hpet() -> u64 {
+    let hpet_hz = hpet::frequency();
+    let run_ticks = hpet_hz * CALIBRATION_MS as u64 / 1000;
+    // a 32 bit counter can wrap during a run
+    let mask = if hpet::is_64_bit() { u64::MAX } else { u32::MAX as u64 };
+    (0..CALIBRATION_RUNS)
+        .map(|_| {
+            without_interrupts(|| {
+                let hpet_start = hpet::counter();
+                let start = read();
+                let mut hpet_ticks = 0;
+                while hpet_ticks < run_ticks {
+                    core::hint::spin_loop();
+                    hpet_ticks = hpet::counter().wrapping_sub(hpet_start) & mask;
+                }
+                let cycles = read() - start;
+                (cycles as u128 * hpet_hz as u128 / hpet_ticks as u128) as u64
+            })
+        })
+        .min()
+        .unwrap_or(0)
+}
+
+pub fn reference() -> Reference {
+    if CALIBRATED_WITH_HPET.load(Ordering::SeqCst) {
+        Reference::Hpet
+    } else {
+        Reference::Pit
+    }
+}
+
+// TSC cycles per second as measured by calibrate
+pub fn frequency() -> u64 {
+    TSC_HZ.load(Ordering::SeqCst)
+}
+
+// whether calibrate found an invariant TSC
+pub fn is_invariant() -> bool {
+    INVARIANT.load(Ordering::SeqCst)
+}
+
+fn cycles_to_duration(cycles: u64) -> Duration {
+    let hz = frequency();
+    assert!(hz != 0, "TSC not calibrated");
+    Duration::from_nanos((cycles as u128 * 1_000_000_000 / hz as u128) as u64)
+}
+
+fn duration_to_cycles(duration: Duration) -> u64 {
+    (duration.as_nanos() * frequency() as u128 / 1_000_000_000) as u64
+}
+
+/**
+ * a point in time, with nanosecond resolution
+ * only comparable to other Instants - it's not tied to any date
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant(u64);
+
+impl Instant{
+    pub fn now() -> Instant {
+        Instant(read())
+    }
+
+    // zero if earlier is actually later
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        cycles_to_duration(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+}
+
+impl Add<Duration> for Instant{
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        Instant(self.0 + duration_to_cycles(duration))
+    }
+}
+
+impl Sub<Instant> for Instant{
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+#[test_case]
+fn test_calibrated_against_the_hpet_when_there_is_one(){
+    let expected = if hpet::is_enabled() { Reference::Hpet } else { Reference::Pit };
+    assert_eq!(reference(), expected);
+    assert!(frequency() != 0);
+}
+
+#[test_case]
+fn test_instant_is_monotonic(){
+    let first = Instant::now();
+    let second = Instant::now();
+    assert!(second >= first);
+}
+
+#[test_case]
+fn test_instant_agrees_with_ticks(){
+    let start_ticks = super::ticks();
+    let start = Instant::now();
+    // 10 ticks at 1000 Hz, counted from the start of a tick so a partial one doesn't count
+    // the upper bound is loose - a busy host can stall the guest for a while at any point
+    while super::ticks() == start_ticks {
+        x86_64::instructions::hlt();
+    }
+    let tick_start = Instant::now();
+    while super::ticks() < start_ticks + 11 {
+        x86_64::instructions::hlt();
+    }
+    let elapsed = tick_start.elapsed();
+    assert!(elapsed >= Duration::from_millis(8), "{:?}", elapsed);
+    assert!(elapsed <= Duration::from_millis(100), "{:?}", elapsed);
+    assert!(tick_start > start);
+}
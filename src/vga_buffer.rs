@@ -143,6 +143,14 @@ impl Writer{
         self.column_position = 0;
      }
 
+     // blank every row and start over at the beginning of the bottom one
+     pub fn clear_screen(&mut self){
+        for row in 0..BUFFER_HEIGHT{
+            self.clear_row(row);
+        }
+        self.column_position = 0;
+     }
+
      fn clear_row(&mut self, row: usize){
         //create a blank character, write it into the vga buffer
         let blank = ScreenCharacter{
@@ -194,6 +202,14 @@ pub fn _print(args: fmt::Arguments){
     });
 }
 
+// ex: for Ctrl+L
+pub fn clear_screen(){
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        WRITER.lock().clear_screen();
+    });
+}
+
 //builds off print fn
 #[macro_export]
 macro_rules! print {
@@ -0,0 +1,28 @@
+// testing that an x87 instruction with the task switched flag set is caught as device not available
+// the kernel's own handler has to report vector 7 before it panics
+
+#![no_std]
+#![no_main]
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use learning_os::exception_test::{self, Expected};
+use x86_64::structures::idt::ExceptionVector;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    let expected = Expected { vector: ExceptionVector::DeviceNotAvailable, error_code: None };
+    exception_test::run(boot_info, expected, || {
+        use x86_64::registers::control::{Cr0, Cr0Flags};
+        // with the task switched flag set any x87 instruction faults
+        unsafe {
+            Cr0::update(|flags| flags.insert(Cr0Flags::TASK_SWITCHED));
+            core::arch::asm!("fninit");
+        }
+    })
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    exception_test::panic_handler(info)
+}
@@ -0,0 +1,32 @@
+// testing that dividing by zero is caught as a divide error
+// the kernel's own handler has to report vector 0 before it panics
+
+#![no_std]
+#![no_main]
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use learning_os::exception_test::{self, Expected};
+use x86_64::structures::idt::ExceptionVector;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    let expected = Expected { vector: ExceptionVector::Division, error_code: None };
+    exception_test::run(boot_info, expected, || {
+        // rust checks for division by 0 itself, so do the div by hand
+        unsafe {
+            core::arch::asm!(
+                "xor edx, edx",
+                "mov eax, 1",
+                "xor ecx, ecx",
+                "div ecx",
+                out("eax") _, out("ecx") _, out("edx") _,
+            );
+        }
+    })
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    exception_test::panic_handler(info)
+}
@@ -0,0 +1,27 @@
+// testing that a bad segment load is caught as a general protection fault
+// the kernel's own handler has to report vector 13, and the selector that caused it
+
+#![no_std]
+#![no_main]
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use learning_os::exception_test::{self, Expected};
+use x86_64::structures::idt::{ExceptionVector, SelectorErrorCode};
+
+entry_point!(main);
+
+// the selector loaded below - gdt index 0x1fff
+static ERROR_CODE: SelectorErrorCode = SelectorErrorCode::new_truncate(0xfff8);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    let expected = Expected { vector: ExceptionVector::GeneralProtection, error_code: Some(&ERROR_CODE) };
+    // the gdt is nowhere near this big, so loading the selector faults
+    exception_test::run(boot_info, expected, || unsafe {
+        core::arch::asm!("mov ax, 0xfff8", "mov ds, ax", out("ax") _)
+    })
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    exception_test::panic_handler(info)
+}
@@ -0,0 +1,22 @@
+// testing that an undefined instruction is caught as an invalid opcode
+// the kernel's own handler has to report vector 6 before it panics
+
+#![no_std]
+#![no_main]
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use learning_os::exception_test::{self, Expected};
+use x86_64::structures::idt::ExceptionVector;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    let expected = Expected { vector: ExceptionVector::InvalidOpcode, error_code: None };
+    // ud2 is guaranteed to be undefined
+    exception_test::run(boot_info, expected, || unsafe { core::arch::asm!("ud2") })
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    exception_test::panic_handler(info)
+}
@@ -0,0 +1,25 @@
+// testing that going through a missing IDT entry is caught as segment not present
+// the kernel's own handler has to report vector 11, and decode the error code as that IDT entry
+
+#![no_std]
+#![no_main]
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use learning_os::exception_test::{self, Expected};
+use x86_64::structures::idt::{ExceptionVector, SelectorErrorCode};
+
+entry_point!(main);
+
+// idt entry 0x80 - the index, with the bit saying it's in the IDT
+static ERROR_CODE: SelectorErrorCode = SelectorErrorCode::new_truncate(0x80 << 3 | 0b10);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    let expected = Expected { vector: ExceptionVector::SegmentNotPresent, error_code: Some(&ERROR_CODE) };
+    // nothing is ever installed at 0x80, so its gate isn't present
+    exception_test::run(boot_info, expected, || unsafe { core::arch::asm!("int 0x80") })
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    exception_test::panic_handler(info)
+}
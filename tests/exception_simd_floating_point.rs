@@ -0,0 +1,46 @@
+// testing that an unmasked SSE divide by zero is caught as a SIMD floating point exception
+// the kernel's own handler has to report vector 19, and decode MXCSR into what went wrong
+
+#![no_std]
+#![no_main]
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use learning_os::exception_test::{self, Expected};
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+use x86_64::registers::mxcsr::{self, MxCsr};
+use x86_64::structures::idt::ExceptionVector;
+
+entry_point!(main);
+
+static ERROR_CODE: MxCsr = MxCsr::DIVIDE_BY_ZERO;
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    let expected = Expected { vector: ExceptionVector::SimdFloatingPoint, error_code: Some(&ERROR_CODE) };
+    exception_test::run(boot_info, expected, || {
+        // the kernel is built without sse, so turn it on by hand and have it raise exceptions
+        unsafe {
+            Cr0::update(|flags| {
+                flags.remove(Cr0Flags::EMULATE_COPROCESSOR);
+                flags.insert(Cr0Flags::MONITOR_COPROCESSOR);
+            });
+            Cr4::update(|flags| flags.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE));
+        }
+        // dividing by zero faults instead of quietly giving infinity
+        mxcsr::write(MxCsr::default() - MxCsr::DIVIDE_BY_ZERO_MASK);
+        // 1.0 / 0.0 - the kernel never uses the xmm registers, nothing to save
+        unsafe {
+            core::arch::asm!(
+                "mov eax, 1",
+                "cvtsi2ss xmm0, eax",
+                "xorps xmm1, xmm1",
+                "divss xmm0, xmm1",
+                out("eax") _,
+            );
+        }
+    })
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    exception_test::panic_handler(info)
+}
@@ -0,0 +1,106 @@
+// heap allocations need memory set up, so this test boots with BootInfo
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(learning_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+extern crate alloc;
+use alloc::{boxed::Box, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use learning_os::allocator::{self, HEAP_MAX_SIZE, HEAP_SIZE};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    unsafe { learning_os::memory::init_from_boot_info(boot_info) };
+    learning_os::init();
+    allocator::init_heap().expect("heap initialization failed");
+    test_main();
+    learning_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    learning_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn simple_allocation(){
+    let heap_value_1 = Box::new(41);
+    let heap_value_2 = Box::new(13);
+    assert_eq!(*heap_value_1, 41);
+    assert_eq!(*heap_value_2, 13);
+}
+
+#[test_case]
+fn large_vec(){
+    let n = 1000;
+    let mut vec = Vec::new();
+    for i in 0..n {
+        vec.push(i);
+    }
+    // sum of 0..n
+    assert_eq!(vec.iter().sum::<u64>(), (n - 1) * n / 2);
+}
+
+// more total allocations than fit in the heap - only works if freed memory is reused
+#[test_case]
+fn many_boxes(){
+    for i in 0..HEAP_SIZE {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+    }
+}
+
+// a long lived allocation in the middle of many short lived ones
+// the bump allocator only keeps up because each box is the latest allocation when it's freed, so it gets rolled back
+#[test_case]
+fn many_boxes_long_lived(){
+    let long_lived = Box::new(1);
+    for i in 0..HEAP_SIZE {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+    }
+    assert_eq!(*long_lived, 1);
+}
+
+// mixes sizes so blocks of every class, and the fallback, get freed and reused
+#[test_case]
+fn mixed_sizes_reused(){
+    let mut long_lived = Vec::new();
+    for round in 0..200 {
+        let small = Box::new([round as u8; 24]);
+        let medium = Vec::<u64>::with_capacity(100);
+        let large = Vec::<u8>::with_capacity(4096);
+        assert_eq!(small[23], round as u8);
+        assert!(medium.capacity() >= 100 && large.capacity() >= 4096);
+        if round % 20 == 0 {
+            long_lived.push(Box::new(round));
+        }
+    }
+    for (i, value) in long_lived.iter().enumerate() {
+        assert_eq!(**value, i * 20);
+    }
+}
+
+// bigger than the initial heap, so the heap has to map more pages to fit it
+#[test_case]
+fn heap_grows_past_initial_size(){
+    let n = HEAP_SIZE;
+    let mut vec = Vec::<u64>::with_capacity(n);
+    for i in 0..n as u64 {
+        vec.push(i);
+    }
+    assert_eq!(vec[n - 1], n as u64 - 1);
+}
+
+// past the ceiling the allocator reports failure instead of growing forever
+// without mapping pages for an allocation that was never going to fit
+#[test_case]
+fn heap_stops_at_max_size(){
+    let heap_end = allocator::heap_end();
+    let mut vec = Vec::<u8>::new();
+    assert!(vec.try_reserve_exact(HEAP_MAX_SIZE + 1).is_err());
+    assert_eq!(allocator::heap_end(), heap_end);
+}
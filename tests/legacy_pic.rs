@@ -0,0 +1,51 @@
+// boots with the APIC skipped, so the 8259 fallback keeps working
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(learning_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use learning_os::interrupts::{self, InterruptController};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    unsafe { learning_os::memory::init_from_boot_info(boot_info) };
+    learning_os::init_with(InterruptController::Pic);
+    test_main();
+    learning_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    learning_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn pic_is_used(){
+    assert_eq!(interrupts::interrupt_controller(), InterruptController::Pic);
+}
+
+#[test_case]
+fn timer_interrupt_arrives(){
+    // hlt only returns once an interrupt comes in
+    x86_64::instructions::hlt();
+}
+
+#[test_case]
+fn irq_7_without_in_service_bit_is_spurious(){
+    // raised in software, so the PIC never marked it as in service
+    let before = interrupts::stats::spurious_count();
+    unsafe { core::arch::asm!("int 39") };
+    assert_eq!(interrupts::stats::spurious_count(), before + 1);
+}
+
+#[test_case]
+fn irq_15_without_in_service_bit_is_spurious(){
+    let before = interrupts::stats::spurious_count();
+    unsafe { core::arch::asm!("int 47") };
+    assert_eq!(interrupts::stats::spurious_count(), before + 1);
+    // the EOI that went to the primary must not have broken anything
+    x86_64::instructions::hlt();
+}
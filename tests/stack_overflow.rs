@@ -3,20 +3,29 @@
 #![no_std]
 #![no_main]
 #![feature(abi_x86_interrupt)]
+use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 use lazy_static::lazy_static;
 use x86_64::structures::idt::InterruptDescriptorTable;
-use learning_os::{exit_qemu, QemuExitCode, serial_print, serial_println};
+use learning_os::{exit_qemu, memory, QemuExitCode, serial_print, serial_println};
 use x86_64::structures::idt::InterruptStackFrame;
 
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
     serial_print!("Testing stack overflow\t");
+    // the double fault stack is mapped through the page tables
+    unsafe { memory::init_from_boot_info(boot_info) };
     learning_os::gdt::init();
     init_test_idt();
+    // overflow a stack whose guard page is registered, so the fault can be traced back to it
+    let stack = memory::stack::allocate("test stack", 4).expect("stack allocation failed");
+    unsafe { memory::stack::switch_to(&stack, overflow_test_stack) }
+}
+
+extern "C" fn overflow_test_stack() -> ! {
     stack_overflow();
     panic!("Execution continued after stack overflow")
-
 }
 
 #[panic_handler]
@@ -48,11 +57,22 @@ extern "x86-interrupt" fn test_double_fault_handler(
     _stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
-    serial_println!("Printing from double fault test...[ok]");
-    exit_qemu(QemuExitCode::Success);
+    use x86_64::registers::control::Cr2;
+    // the overflow should have run into the test stack's guard page
+    match memory::stack::guard_page_owner(Cr2::read()) {
+        Some("test stack") => {
+            serial_println!("Printing from double fault test...[ok]");
+            exit_qemu(QemuExitCode::Success);
+        }
+        owner => {
+            serial_println!("[failed]\n");
+            serial_println!("[Error info: guard page owner was {:?}]\n", owner);
+            exit_qemu(QemuExitCode::Failed);
+        }
+    }
     loop{}
 }
 
 pub fn init_test_idt(){
     TEST_IDT.load();
-}
\ No newline at end of file
+}
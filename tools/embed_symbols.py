@@ -0,0 +1,115 @@
+#!/usr/bin/env python3
+# Gregory Vincent
+# fills the kernel's .ksymtab section with function names from its own .symtab
+# see src/symbols.rs for the table layout - this has to stay in sync with it
+# usage: tools/embed_symbols.py path/to/kernel-elf
+import re
+import struct
+import sys
+
+MAGIC = b"KSYMTAB\0"
+HEADER = struct.Struct("<8sII")
+ENTRY = struct.Struct("<QII")
+SECTION_HEADER = struct.Struct("<IIQQQQIIQQ")
+SYMBOL = struct.Struct("<IBBHQQ")
+STT_FUNC = 2
+
+# legacy rust mangling escapes, ex: $LT$ is <
+ESCAPES = {
+    "$SP$": "@", "$BP$": "*", "$RF$": "&", "$LT$": "<", "$GT$": ">",
+    "$LP$": "(", "$RP$": ")", "$C$": ",",
+}
+
+
+def demangle(name):
+    # _ZN 3foo 3bar 17h0123456789abcdefE -> foo::bar
+    if not name.startswith("_ZN") or not name.endswith("E"):
+        return name
+    rest = name[3:-1]
+    parts = []
+    while rest:
+        match = re.match(r"(\d+)", rest)
+        if not match:
+            return name
+        length = int(match.group(1))
+        start = len(match.group(1))
+        parts.append(rest[start:start + length])
+        rest = rest[start + length:]
+    # the last part is a hash of the crate and signature
+    if parts and re.fullmatch(r"h[0-9a-f]{16}", parts[-1]):
+        parts.pop()
+    demangled = []
+    for part in parts:
+        if part.startswith("_$"):
+            part = part[1:]
+        part = part.replace("..", "::")
+        for escape, char in ESCAPES.items():
+            part = part.replace(escape, char)
+        part = re.sub(r"\$u([0-9a-f]+)\$", lambda m: chr(int(m.group(1), 16)), part)
+        demangled.append(part)
+    return "::".join(demangled)
+
+
+def read_sections(elf):
+    if elf[:4] != b"\x7fELF" or elf[4] != 2 or elf[5] != 1:
+        sys.exit("not a little endian 64 bit ELF")
+    section_offset, = struct.unpack_from("<Q", elf, 0x28)
+    entry_size, count, names_index = struct.unpack_from("<HHH", elf, 0x3a)
+    sections = [SECTION_HEADER.unpack_from(elf, section_offset + i * entry_size) for i in range(count)]
+    names = sections[names_index]
+    def section_name(section):
+        start = names[4] + section[0]
+        return elf[start:elf.index(b"\0", start)].decode()
+    return {section_name(section): section for section in sections}, sections
+
+
+def functions(elf, sections, all_sections):
+    symtab = sections.get(".symtab")
+    if symtab is None:
+        sys.exit("the kernel has no .symtab - was it stripped?")
+    strtab = all_sections[symtab[6]]
+    found = {}
+    for offset in range(symtab[4], symtab[4] + symtab[5], SYMBOL.size):
+        name_offset, info, _, section_index, address, size = SYMBOL.unpack_from(elf, offset)
+        if info & 0xf != STT_FUNC or address == 0 or section_index == 0:
+            continue
+        start = strtab[4] + name_offset
+        name = elf[start:elf.index(b"\0", start)].decode(errors="replace")
+        # aliases at the same address - keep the first
+        found.setdefault(address, (min(size, 0xffff_ffff), demangle(name)))
+    return sorted((address, size, name) for address, (size, name) in found.items())
+
+
+def build_table(symbols):
+    entries = bytearray()
+    names = bytearray()
+    for address, size, name in symbols:
+        entries += ENTRY.pack(address, size, len(names))
+        names += name.encode() + b"\0"
+    return HEADER.pack(MAGIC, len(symbols), len(names)) + entries + names
+
+
+def main():
+    if len(sys.argv) != 2:
+        sys.exit("usage: embed_symbols.py <kernel-elf>")
+    path = sys.argv[1]
+    with open(path, "rb") as file:
+        elf = bytearray(file.read())
+    sections, all_sections = read_sections(elf)
+    ksymtab = sections.get(".ksymtab")
+    if ksymtab is None:
+        sys.exit(f"{path} has no .ksymtab section to fill")
+    offset, size = ksymtab[4], ksymtab[5]
+    if elf[offset:offset + len(MAGIC)] != MAGIC:
+        sys.exit(f"{path}: .ksymtab doesn't start with the table magic")
+    table = build_table(functions(elf, sections, all_sections))
+    if len(table) > size:
+        sys.exit(f"symbol table is {len(table)} bytes, only {size} reserved - raise SYMBOL_TABLE_SIZE in src/symbols.rs")
+    # zero the rest so embedding twice gives the same result
+    elf[offset:offset + size] = table + bytes(size - len(table))
+    with open(path, "wb") as file:
+        file.write(elf)
+
+
+if __name__ == "__main__":
+    main()
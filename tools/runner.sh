@@ -0,0 +1,6 @@
+#!/bin/sh
+# cargo runner - embeds the symbol table into the kernel, then boots it in qemu like before
+# $1 is the kernel ELF cargo just built
+set -e
+python3 "$(dirname "$0")/embed_symbols.py" "$1"
+exec bootimage runner "$@"